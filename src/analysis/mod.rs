@@ -0,0 +1,1233 @@
+use crate::parser::is_identifier;
+use crate::{BdlArg, BdlBranchOption, BdlCondition, BdlContentElement, BdlDestination, BdlDocument, BdlError, BdlValue, DestinationKind, normalize_keyword};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Whether the same keyword may appear on several options of one node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeywordPolicy {
+    /// Every keyword must be unique within its node
+    #[default]
+    Strict,
+    /// Duplicates are allowed, e.g. when conditions decide between them
+    Lenient,
+}
+
+/// Type a function argument can be declared with in a `FunctionSignature`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    String,
+    Integer,
+    /// Any number, including integers
+    Number,
+    Boolean,
+    List,
+    Map,
+}
+
+impl ArgType {
+    fn accepts(self, value: &BdlValue) -> bool {
+        matches!(
+            (self, value),
+            (ArgType::String, BdlValue::String(_))
+                | (ArgType::Integer, BdlValue::Integer(_))
+                | (ArgType::Number, BdlValue::Integer(_) | BdlValue::Number(_))
+                | (ArgType::Boolean, BdlValue::Boolean(_))
+                | (ArgType::List, BdlValue::List(_))
+                | (ArgType::Map, BdlValue::Map(_))
+        )
+    }
+}
+
+/// The declared shape of a host function
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FunctionSignature {
+    /// One entry per argument, in order; `None` accepts any value
+    pub args: Vec<Option<ArgType>>,
+    /// Number of values the function returns
+    pub results: usize,
+}
+
+/// Function signatures by name, checked by `validate_function_calls`
+pub type FunctionSchema = HashMap<String, FunctionSignature>;
+
+/// Summary of how many options nodes offer
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchingStats {
+    /// Mean number of options per node (0 for an empty document)
+    pub mean: f64,
+    /// Largest number of options on a single node
+    pub max: usize,
+    /// Number of nodes for each option count
+    pub histogram: BTreeMap<usize, usize>,
+}
+
+impl BdlDocument {
+    /// Compute a branching complexity score for every node
+    ///
+    /// The score is the number of options plus one extra point for each
+    /// conditional option, since a conditional branch adds a decision on top
+    /// of the choice itself. Nodes without options score zero.
+    pub fn complexity(&self) -> HashMap<String, usize> {
+        self.nodes
+            .iter()
+            .map(|(name, node)| {
+                let conditional = node.options.iter().filter(|o| o.condition.is_some()).count();
+                (name.clone(), node.options.len() + conditional)
+            })
+            .collect()
+    }
+
+    /// Collect the nodes reachable from `entry` under a fixed set of variables
+    ///
+    /// Only local node edges are followed, and an option is only taken if its
+    /// condition (if any) passes against `env`. The entry itself is included
+    /// when it exists.
+    pub fn reachable_under(&self, entry: &str, env: &HashMap<String, BdlValue>) -> HashSet<String> {
+        let mut reached = HashSet::new();
+        let mut stack = vec![entry];
+
+        while let Some(name) = stack.pop() {
+            let Some(node) = self.nodes.get(name) else {
+                continue;
+            };
+            if !reached.insert(name.to_string()) {
+                continue;
+            }
+
+            for option in &node.options {
+                if option.condition.as_ref().is_some_and(|c| !c.evaluate(env)) {
+                    continue;
+                }
+                if let Some(target) = option.destination.local_target(name) {
+                    stack.push(target);
+                }
+            }
+        }
+
+        reached
+    }
+
+    /// Simplify the document for a fixed set of known variables
+    ///
+    /// Options whose condition is false under `env` are removed, and
+    /// conditions that are true are dropped. Conditions on variables missing
+    /// from `env` are left in place to be decided at runtime.
+    pub fn specialize(&self, env: &HashMap<String, BdlValue>) -> BdlDocument {
+        let mut doc = self.clone();
+
+        for node in doc.nodes.values_mut() {
+            node.options.retain_mut(|option| {
+                let Some(condition) = &option.condition else {
+                    return true;
+                };
+                if !env.contains_key(&condition.variable) {
+                    return true;
+                }
+                if condition.evaluate(env) {
+                    option.condition = None;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+
+        doc
+    }
+
+    /// The longest acyclic run of nodes from `entry` to a point where the dialog ends
+    ///
+    /// A path may end at a node with an `exit` option or with no options at
+    /// all. Only local node edges are followed and conditions are ignored.
+    /// Among paths of equal length the first in option order wins. Returns
+    /// an empty list when no such path exists.
+    pub fn longest_path(&self, entry: &str) -> Vec<String> {
+        let mut path = Vec::new();
+        let mut best = Vec::new();
+        self.extend_longest_path(entry, &mut path, &mut best);
+        best.into_iter().map(str::to_string).collect()
+    }
+
+    fn extend_longest_path<'a>(&'a self, name: &'a str, path: &mut Vec<&'a str>, best: &mut Vec<&'a str>) {
+        let Some(node) = self.nodes.get(name) else {
+            return;
+        };
+        if path.contains(&name) {
+            return;
+        }
+
+        path.push(name);
+        let ends_here = node.options.is_empty()
+            || node.options.iter().any(|o| matches!(o.destination, BdlDestination::Exit));
+        if ends_here && path.len() > best.len() {
+            *best = path.clone();
+        }
+        for option in &node.options {
+            if let Some(target) = option.destination.local_target(name) {
+                self.extend_longest_path(target, path, best);
+            }
+        }
+        path.pop();
+    }
+
+    /// Nodes that no path from `start` can reach, sorted by name
+    ///
+    /// Conditions are ignored; file transfers and exits end a path. Nodes
+    /// tagged `#allow-unreachable` are never reported.
+    pub fn unreachable_nodes(&self, start: &str) -> Vec<String> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![start];
+
+        while let Some(name) = stack.pop() {
+            let Some(node) = self.nodes.get(name) else {
+                continue;
+            };
+            if !visited.insert(name) {
+                continue;
+            }
+            for option in &node.options {
+                if let Some(target) = option.destination.local_target(name) {
+                    stack.push(target);
+                }
+            }
+        }
+
+        let mut unreachable: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|(name, node)| !visited.contains(name.as_str()) && !node.allows_unreachable())
+            .map(|(name, _)| name.clone())
+            .collect();
+        unreachable.sort();
+        unreachable
+    }
+
+    /// Every simple cycle formed by local node references
+    ///
+    /// Each cycle starts at its alphabetically smallest node and follows
+    /// options in source order, so `A -> B -> A` is reported once as
+    /// `["A", "B"]` and a self-loop as a single name. Cycles are sorted by
+    /// their starting node, then discovery order.
+    pub fn find_cycles(&self) -> Vec<Vec<String>> {
+        let mut names: Vec<&str> = self.nodes.keys().map(String::as_str).collect();
+        names.sort();
+
+        let mut cycles = Vec::new();
+        for root in names {
+            let mut path = vec![root];
+            self.extend_cycles(root, root, &mut path, &mut cycles);
+        }
+        cycles
+    }
+
+    fn extend_cycles<'a>(&'a self, root: &str, name: &'a str, path: &mut Vec<&'a str>, cycles: &mut Vec<Vec<String>>) {
+        let mut targets: Vec<&str> = Vec::new();
+        for option in &self.nodes[name].options {
+            if let Some(target) = option.destination.local_target(name) {
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+
+        for target in targets {
+            if target == root {
+                cycles.push(path.iter().map(|n| n.to_string()).collect());
+            } else if target > root && self.nodes.contains_key(target) && !path.contains(&target) {
+                path.push(target);
+                self.extend_cycles(root, target, path, cycles);
+                path.pop();
+            }
+        }
+    }
+
+    /// Whether some path from `entry` reaches an `exit` option
+    ///
+    /// Conditions are ignored and only local node edges are followed, so a
+    /// region that only loops back on itself returns false. Computed
+    /// destinations are not known until runtime and are not followed.
+    pub fn has_reachable_exit(&self, entry: &str) -> bool {
+        let mut visited = HashSet::new();
+        let mut stack = vec![entry];
+
+        while let Some(name) = stack.pop() {
+            let Some(node) = self.nodes.get(name) else {
+                continue;
+            };
+            if !visited.insert(name) {
+                continue;
+            }
+
+            for option in &node.options {
+                match &option.destination {
+                    BdlDestination::Exit => return true,
+                    BdlDestination::Node(target) => stack.push(target),
+                    BdlDestination::FileTransfer { .. } | BdlDestination::Current | BdlDestination::Computed { .. } => {}
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Run the document-wide checks that do not depend on a policy
+    ///
+    /// Currently this requires that every local destination exists and that
+    /// an exit is reachable from `entry`.
+    pub fn validate(&self, entry: &str) -> Result<(), Vec<BdlError>> {
+        let mut errors = Vec::new();
+
+        if let Err(error) = self.validate_references() {
+            errors.push(error);
+        }
+        if !self.has_reachable_exit(entry) {
+            errors.push(BdlError::NodeError(format!("No exit is reachable from '{}'", entry).into()));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Check keyword uniqueness within each node according to `policy`
+    ///
+    /// Under `KeywordPolicy::Strict` every keyword used by more than one
+    /// option of the same node is reported as a `NodeError`. Keywords are
+    /// compared the way the runtime matches them by default, ignoring case
+    /// and extra whitespace, so `{Yes}` and `{yes}` clash. All-of options
+    /// (`{+open +door}`) match on word combinations rather than single
+    /// keywords, so they are not checked.
+    pub fn validate_keywords(&self, policy: KeywordPolicy) -> Result<(), Vec<BdlError>> {
+        if policy == KeywordPolicy::Lenient {
+            return Ok(());
+        }
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut errors = Vec::new();
+        for name in names {
+            let mut seen = HashSet::new();
+            let mut reported = HashSet::new();
+            for keyword in self.nodes[name].options.iter().filter(|o| !o.match_all).flat_map(|o| &o.keywords) {
+                let normalized = normalize_keyword(keyword, false);
+                if !seen.insert(normalized.clone()) && reported.insert(normalized) {
+                    errors.push(BdlError::NodeError(format!(
+                        "Duplicate keyword '{}' in node '{}'",
+                        keyword, name
+                    ).into()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// List `(source_node, missing_target)` for every local reference to an absent node
+    ///
+    /// Sorted by source node, then target.
+    pub fn dangling_references(&self) -> Vec<(String, String)> {
+        let mut dangling: Vec<(String, String)> = self
+            .nodes
+            .values()
+            .flat_map(|node| {
+                node.options.iter().filter_map(move |option| match &option.destination {
+                    BdlDestination::Node(target) if !self.nodes.contains_key(target) => {
+                        Some((node.name.clone(), target.clone()))
+                    }
+                    _ => None,
+                })
+            })
+            .collect();
+
+        dangling.sort();
+        dangling.dedup();
+        dangling
+    }
+
+    /// Every distinct destination of one kind, paired with the node it appears in
+    ///
+    /// Results are sorted by node name, then option position; a node that
+    /// repeats the same destination is listed once.
+    pub fn destinations_of_type(&self, kind: DestinationKind) -> Vec<(&str, &BdlDestination)> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut found: Vec<(&str, &BdlDestination)> = Vec::new();
+        for name in names {
+            for option in &self.nodes[name].options {
+                let entry = (name.as_str(), &option.destination);
+                if option.destination.kind() == kind && !found.contains(&entry) {
+                    found.push(entry);
+                }
+            }
+        }
+        found
+    }
+
+    /// Options that lead to the given node, paired with the node they belong to
+    ///
+    /// Only same-file `@node` and `@self` destinations count. Results are
+    /// sorted by source node, then option position.
+    pub fn predecessors(&self, name: &str) -> Vec<(&str, &BdlBranchOption)> {
+        let mut sources: Vec<&String> = self.nodes.keys().collect();
+        sources.sort();
+
+        sources
+            .into_iter()
+            .flat_map(|source| {
+                let node = &self.nodes[source];
+                node.options
+                    .iter()
+                    .filter(|option| option.destination.local_target(source) == Some(name))
+                    .map(move |option| (node.name.as_str(), option))
+            })
+            .collect()
+    }
+
+    /// Check that every local `@node` destination names an existing node
+    ///
+    /// All dangling references are listed in a single `NodeError`. File
+    /// transfers are skipped since their targets live in other files.
+    pub fn validate_references(&self) -> Result<(), BdlError> {
+        let dangling = self.dangling_references();
+        if dangling.is_empty() {
+            return Ok(());
+        }
+
+        let listed: Vec<String> = dangling
+            .iter()
+            .map(|(source, target)| format!("'{}' -> '{}'", source, target))
+            .collect();
+        Err(BdlError::NodeError(format!("Options point to missing nodes: {}", listed.join(", ")).into()))
+    }
+
+    /// Find options that can never be chosen because an earlier option claims their keyword
+    ///
+    /// An option is shadowed when an earlier option in the same node, with no
+    /// `?{...}` or `~{...}` condition, already uses one of its keywords,
+    /// compared ignoring case and extra whitespace as the runtime does by
+    /// default. All-of options neither claim nor lose keywords. Returns
+    /// `(node, option_index, keyword)` for each shadowed keyword, sorted by
+    /// node then index.
+    pub fn shadowed_options(&self) -> Vec<(String, usize, String)> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut shadowed = Vec::new();
+        for name in names {
+            let mut claimed = HashSet::new();
+            for (index, option) in self.nodes[name].options.iter().enumerate() {
+                if option.match_all {
+                    continue;
+                }
+                for keyword in &option.keywords {
+                    if claimed.contains(&normalize_keyword(keyword, false)) {
+                        shadowed.push((name.clone(), index, keyword.clone()));
+                    }
+                }
+                if option.condition.is_none() && option.enabled.is_none() {
+                    claimed.extend(option.keywords.iter().map(|keyword| normalize_keyword(keyword, false)));
+                }
+            }
+        }
+
+        shadowed
+    }
+
+    /// Compute the mean, maximum and distribution of options per node
+    pub fn branching_stats(&self) -> BranchingStats {
+        let mut histogram = BTreeMap::new();
+        for node in self.nodes.values() {
+            *histogram.entry(node.options.len()).or_insert(0) += 1;
+        }
+
+        let total: usize = self.nodes.values().map(|node| node.options.len()).sum();
+        let mean = if self.nodes.is_empty() {
+            0.0
+        } else {
+            total as f64 / self.nodes.len() as f64
+        };
+
+        BranchingStats {
+            mean,
+            max: histogram.keys().next_back().copied().unwrap_or(0),
+            histogram,
+        }
+    }
+
+    /// Check that no node offers more than `max` options
+    ///
+    /// Every node over the cap is reported as a `NodeError` with its count,
+    /// in node-name order.
+    pub fn check_max_options(&self, max: usize) -> Result<(), Vec<BdlError>> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let errors: Vec<BdlError> = names
+            .into_iter()
+            .filter(|name| self.nodes[*name].options.len() > max)
+            .map(|name| {
+                BdlError::NodeError(format!(
+                    "Node '{}' has {} options, more than the maximum of {}",
+                    name,
+                    self.nodes[name].options.len(),
+                    max
+                ).into())
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Check that every variable reference and function call in node content is well formed
+    ///
+    /// Variable names, function names, variable arguments and result
+    /// variables must all be identifiers. `Text` is not inspected: the parser
+    /// has already turned every unescaped `${...}` and `!{...}` into its own
+    /// element, so markers left in text are literal. Each problem is reported
+    /// as a `ParseError` naming its node, in node-name order.
+    pub fn validate_interpolation(&self) -> Result<(), Vec<BdlError>> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut errors = Vec::new();
+        for name in names {
+            for element in &self.nodes[name].content {
+                if let Err(reason) = check_interpolation(element) {
+                    errors.push(BdlError::ParseError(format!("Node '{}': {}", name, reason).into()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Check every function call against the signatures in `schema`
+    ///
+    /// Each `!{name(...)}` must name a function in the schema, pass as many
+    /// arguments as it declares and bind no more result variables than it
+    /// returns. Literal arguments must fit their declared type, as must
+    /// variables declared with a non-empty default (local defaults win over
+    /// global ones). A `!{name}` destination is called with no arguments and
+    /// uses one result. Each problem is reported as a `VariableError` naming
+    /// its node, in node-name order.
+    pub fn validate_function_calls(&self, schema: &FunctionSchema) -> Result<(), Vec<BdlError>> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut errors = Vec::new();
+        for name in names {
+            let node = &self.nodes[name];
+            let calls = node.content.iter().filter_map(|element| match element {
+                BdlContentElement::FunctionCall { name, args, result_vars } => {
+                    Some((name.as_str(), &args[..], result_vars.len()))
+                }
+                _ => None,
+            });
+            let computed = node.options.iter().filter_map(|option| match &option.destination {
+                BdlDestination::Computed { function } => Some((function.as_str(), &[][..], 1)),
+                _ => None,
+            });
+
+            for (function, args, used) in calls.chain(computed) {
+                if let Err(reason) = self.check_call(schema, function, args, used) {
+                    errors.push(BdlError::VariableError(format!("Node '{}': {}", name, reason).into()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Check one call of `function` that uses `used` of its results
+    fn check_call(&self, schema: &FunctionSchema, function: &str, args: &[BdlArg], used: usize) -> Result<(), String> {
+        let signature = schema
+            .get(function)
+            .ok_or_else(|| format!("function '{}' is not in the schema", function))?;
+        if args.len() != signature.args.len() {
+            return Err(format!(
+                "function '{}' takes {} arguments, called with {}",
+                function,
+                signature.args.len(),
+                args.len()
+            ));
+        }
+        if used > signature.results {
+            return Err(format!("function '{}' returns {} values, {} are used", function, signature.results, used));
+        }
+
+        for (index, (arg, expected)) in args.iter().zip(&signature.args).enumerate() {
+            let value = match arg {
+                BdlArg::Value(value) => Some(value),
+                BdlArg::Variable(variable) => self
+                    .local_vars
+                    .get(variable)
+                    .or_else(|| self.global_vars.as_ref()?.get(variable)),
+            };
+            if let (Some(expected), Some(value)) = (expected, value.filter(|v| **v != BdlValue::Empty)) {
+                if !expected.accepts(value) {
+                    return Err(format!(
+                        "argument {} of function '{}' should be {:?}, got {}",
+                        index + 1,
+                        function,
+                        expected,
+                        arg
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// List every option condition as `(node_name, condition)`
+    ///
+    /// Nodes are visited by name and options in source order.
+    pub fn conditions(&self) -> Vec<(&str, &BdlCondition)> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        names
+            .into_iter()
+            .flat_map(|name| {
+                self.nodes[name]
+                    .options
+                    .iter()
+                    .filter_map(move |option| option.condition.as_ref().map(|c| (name.as_str(), c)))
+            })
+            .collect()
+    }
+}
+
+/// Check the names used by one variable or function call element
+fn check_interpolation(element: &BdlContentElement) -> Result<(), String> {
+    let check = |kind: &str, name: &str| {
+        if is_identifier(name) {
+            Ok(())
+        } else {
+            Err(format!("invalid {} '{}'", kind, name))
+        }
+    };
+
+    match element {
+        BdlContentElement::Variable(name) | BdlContentElement::FormattedVariable { name, .. } => check("variable", name),
+        BdlContentElement::Conditional { condition, .. } => check("condition variable", &condition.variable),
+        BdlContentElement::FunctionCall { name, args, result_vars } => {
+            check("function name", name)?;
+            for arg in args {
+                if let BdlArg::Variable(variable) = arg {
+                    check("argument variable", variable)?;
+                }
+            }
+            result_vars.iter().try_for_each(|variable| check("result variable", variable))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ArgType, BranchingStats, FunctionSchema, FunctionSignature, KeywordPolicy};
+    use std::collections::BTreeMap;
+    use crate::{BdlArg, BdlBranchOption, BdlContentElement, BdlDestination, BdlDocument, BdlError, BdlNode, BdlValue, DestinationKind};
+    use std::collections::{HashMap, HashSet};
+    use crate::fixtures::{node, option, when};
+
+    #[test]
+    fn test_complexity_relative_scores() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut branchy = BdlNode::new("branchy".to_string());
+        branchy.add_option(when("has_a", option(&["a"], node("simple"))));
+        branchy.add_option(when("has_b", option(&["b"], node("simple"))));
+        branchy.add_option(when("has_c", option(&["c"], node("simple"))));
+        doc.add_node(branchy).unwrap();
+
+        let mut simple = BdlNode::new("simple".to_string());
+        simple.add_option(option(&["next"], node("branchy")));
+        doc.add_node(simple).unwrap();
+
+        let scores = doc.complexity();
+        assert_eq!(scores["simple"], 1);
+        assert_eq!(scores["branchy"], 6);
+        assert!(scores["branchy"] > scores["simple"]);
+    }
+
+    #[test]
+    fn test_reachable_under_environment() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["shop"], node("shop")));
+        start.add_option(when("has_key", option(&["vault"], node("vault"))));
+        doc.add_node(start).unwrap();
+
+        let mut vault = BdlNode::new("vault".to_string());
+        vault.add_option(option(&["deeper"], node("treasure")));
+        doc.add_node(vault).unwrap();
+
+        doc.add_node(BdlNode::new("shop".to_string())).unwrap();
+        doc.add_node(BdlNode::new("treasure".to_string())).unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("has_key".to_string(), BdlValue::Boolean(false));
+        let reached = doc.reachable_under("start", &env);
+        let expected: HashSet<String> = ["start", "shop"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(reached, expected);
+
+        env.insert("has_key".to_string(), BdlValue::Boolean(true));
+        let reached = doc.reachable_under("start", &env);
+        assert_eq!(reached.len(), 4);
+        assert!(reached.contains("treasure"));
+    }
+
+    #[test]
+    fn test_conditions_collected() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut gate = BdlNode::new("gate".to_string());
+        gate.add_option(when("has_key", option(&["open"], node("yard"))));
+        gate.add_option(option(&["knock"], node("yard")));
+        gate.add_option(when("has_rope", option(&["climb"], node("yard"))));
+        doc.add_node(gate).unwrap();
+
+        let mut yard = BdlNode::new("yard".to_string());
+        yard.add_option(when("gate_open", option(&["back"], node("gate"))));
+        doc.add_node(yard).unwrap();
+
+        let conditions: Vec<(&str, &str)> = doc
+            .conditions()
+            .into_iter()
+            .map(|(node, c)| (node, c.variable.as_str()))
+            .collect();
+        assert_eq!(
+            conditions,
+            vec![("gate", "has_key"), ("gate", "has_rope"), ("yard", "gate_open")]
+        );
+    }
+
+    #[test]
+    fn test_keyword_policy() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut door = BdlNode::new("door".to_string());
+        door.add_option(when("has_key", option(&["open"], node("inside"))));
+        door.add_option(option(&["open"], node("locked")));
+        door.add_option(option(&["leave"], node("street")));
+        // Word combinations do not clash with single keywords
+        door.add_option(BdlBranchOption { match_all: true, ..option(&["open", "door"], node("inside")) });
+        doc.add_node(door).unwrap();
+
+        assert!(doc.shadowed_options().iter().all(|(_, index, _)| *index != 3));
+        let errors = doc.validate_keywords(KeywordPolicy::Strict).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].to_string().contains("'open'"));
+
+        assert!(doc.validate_keywords(KeywordPolicy::Lenient).is_ok());
+    }
+
+    #[test]
+    fn test_keywords_compared_like_runtime_input() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut gate = BdlNode::new("gate".to_string());
+        gate.add_option(option(&["Yes"], node("inside")));
+        gate.add_option(option(&["yes"], node("street")));
+        gate.add_option(option(&["walk  away"], node("street")));
+        gate.add_option(option(&["Walk away"], node("inside")));
+        doc.add_node(gate).unwrap();
+
+        assert_eq!(
+            doc.shadowed_options(),
+            vec![
+                ("gate".to_string(), 1, "yes".to_string()),
+                ("gate".to_string(), 3, "Walk away".to_string()),
+            ]
+        );
+        let errors = doc.validate_keywords(KeywordPolicy::Strict).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].to_string().contains("'yes'"));
+        assert!(errors[1].to_string().contains("'Walk away'"));
+    }
+
+    #[test]
+    fn test_dangling_reference_after_removal() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["shop"], node("shop")));
+        start.add_option(option(&["inn"], node("inn")));
+        doc.add_node(start).unwrap();
+        doc.add_node(BdlNode::new("shop".to_string())).unwrap();
+        doc.add_node(BdlNode::new("inn".to_string())).unwrap();
+
+        assert!(doc.dangling_references().is_empty());
+
+        doc.nodes.remove("shop");
+        assert_eq!(
+            doc.dangling_references(),
+            vec![("start".to_string(), "shop".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_shadowed_conditional_option() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut hall = BdlNode::new("hall".to_string());
+        hall.add_option(option(&["go"], node("street")));
+        hall.add_option(when("has_map", option(&["go"], node("secret"))));
+        hall.add_option(when("tired", option(&["stay"], node("hall"))));
+        hall.add_option(option(&["stay"], node("bed")));
+        doc.add_node(hall).unwrap();
+
+        assert_eq!(
+            doc.shadowed_options(),
+            vec![("hall".to_string(), 1, "go".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_branching_stats() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut hub = BdlNode::new("hub".to_string());
+        for keyword in ["a", "b", "c", "d"] {
+            hub.add_option(option(&[keyword], node("leaf")));
+        }
+        doc.add_node(hub).unwrap();
+
+        let mut middle = BdlNode::new("middle".to_string());
+        middle.add_option(option(&["x"], node("hub")));
+        middle.add_option(option(&["y"], node("leaf")));
+        doc.add_node(middle).unwrap();
+
+        doc.add_node(BdlNode::new("leaf".to_string())).unwrap();
+
+        let stats = doc.branching_stats();
+        assert_eq!(stats.mean, 2.0);
+        assert_eq!(stats.max, 4);
+        assert_eq!(stats.histogram, BTreeMap::from([(0, 1), (2, 1), (4, 1)]));
+
+        assert_eq!(
+            BdlDocument::new(None).branching_stats(),
+            BranchingStats { mean: 0.0, max: 0, histogram: BTreeMap::new() }
+        );
+    }
+
+    #[test]
+    fn test_check_max_options() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut crowded = BdlNode::new("crowded".to_string());
+        for keyword in ["a", "b", "c", "d", "e"] {
+            crowded.add_option(option(&[keyword], node("calm")));
+        }
+        doc.add_node(crowded).unwrap();
+
+        let mut calm = BdlNode::new("calm".to_string());
+        calm.add_option(option(&["back"], node("crowded")));
+        doc.add_node(calm).unwrap();
+
+        let errors = doc.check_max_options(4).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        let message = errors[0].to_string();
+        assert!(message.contains("'crowded'") && message.contains("5 options"));
+
+        assert!(doc.check_max_options(5).is_ok());
+    }
+
+    #[test]
+    fn test_predecessors() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut square = BdlNode::new("square".to_string());
+        square.add_option(option(&["inn"], node("inn")));
+        square.add_option(option(&["market"], node("market")));
+        doc.add_node(square).unwrap();
+
+        let mut market = BdlNode::new("market".to_string());
+        market.add_option(when("tired", option(&["rest"], node("inn"))));
+        doc.add_node(market).unwrap();
+        doc.add_node(BdlNode::new("inn".to_string())).unwrap();
+
+        let found: Vec<(&str, &str)> = doc
+            .predecessors("inn")
+            .into_iter()
+            .map(|(source, option)| (source, option.keywords[0].as_str()))
+            .collect();
+        assert_eq!(found, vec![("market", "rest"), ("square", "inn")]);
+        assert!(doc.predecessors("square").is_empty());
+    }
+
+    #[test]
+    fn test_specialize_prunes_dead_options() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut menu = BdlNode::new("menu".to_string());
+        menu.add_option(when("debug_build", option(&["debug"], node("console"))));
+        menu.add_option(when("has_network", option(&["online"], node("lobby"))));
+        menu.add_option(when("has_key", option(&["bonus"], node("vault"))));
+        menu.add_option(option(&["quit"], node("credits")));
+        doc.add_node(menu).unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("debug_build".to_string(), BdlValue::Boolean(false));
+        env.insert("has_network".to_string(), BdlValue::Boolean(true));
+
+        let specialized = doc.specialize(&env);
+        let options = &specialized.nodes["menu"].options;
+        let found: Vec<(&str, Option<&str>)> = options
+            .iter()
+            .map(|o| (o.keywords[0].as_str(), o.condition.as_ref().map(|c| c.variable.as_str())))
+            .collect();
+        assert_eq!(
+            found,
+            vec![("online", None), ("bonus", Some("has_key")), ("quit", None)]
+        );
+
+        // The original is untouched
+        assert_eq!(doc.nodes["menu"].options.len(), 4);
+    }
+
+    #[test]
+    fn test_validate_function_calls() {
+        let content = r#"$local_vars: {
+    sides: 6
+    name: "Ada"
+}
+
+@start
+!{roll(${sides}) -> result} !{greet(name, "hi")}
+{go} -> !{pick_room}
+"#;
+        let doc = crate::parser::BdlParser::new(content.to_string()).parse().unwrap();
+        let mut schema = FunctionSchema::from([
+            ("roll".to_string(), FunctionSignature { args: vec![Some(ArgType::Integer)], results: 1 }),
+            ("greet".to_string(), FunctionSignature { args: vec![Some(ArgType::String), None], results: 0 }),
+            ("pick_room".to_string(), FunctionSignature { args: Vec::new(), results: 1 }),
+        ]);
+        assert!(doc.validate_function_calls(&schema).is_ok());
+
+        // Arity, result count, argument type and a missing signature
+        schema.insert("greet".to_string(), FunctionSignature { args: vec![Some(ArgType::String)], results: 0 });
+        schema.insert("pick_room".to_string(), FunctionSignature { args: Vec::new(), results: 0 });
+        schema.insert("roll".to_string(), FunctionSignature { args: vec![Some(ArgType::Boolean)], results: 1 });
+        let errors = doc.validate_function_calls(&schema).unwrap_err();
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Variable error: Node 'start': argument 1 of function 'roll' should be Boolean, got ${sides}",
+                "Variable error: Node 'start': function 'greet' takes 1 arguments, called with 2",
+                "Variable error: Node 'start': function 'pick_room' returns 0 values, 1 are used",
+            ]
+        );
+
+        schema.remove("roll");
+        let errors = doc.validate_function_calls(&schema).unwrap_err();
+        assert!(matches!(&errors[0], BdlError::VariableError(msg) if msg.contains("'roll' is not in the schema")));
+    }
+
+    #[test]
+    fn test_validate_interpolation() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut greet = BdlNode::new("greet".to_string());
+        greet.add_content(BdlContentElement::Variable("name".to_string()));
+        greet.add_content(BdlContentElement::FunctionCall {
+            name: "roll_dice".to_string(),
+            args: vec![BdlArg::Variable("sides".to_string())],
+            result_vars: vec!["roll".to_string()],
+        });
+        doc.add_node(greet).unwrap();
+
+        let mut shop = BdlNode::new("shop".to_string());
+        shop.add_content(BdlContentElement::Variable("price gold".to_string()));
+        doc.add_node(shop).unwrap();
+
+        let mut bank = BdlNode::new("bank".to_string());
+        bank.add_content(BdlContentElement::FunctionCall {
+            name: "deposit".to_string(),
+            args: Vec::new(),
+            result_vars: vec![String::new()],
+        });
+        doc.add_node(bank).unwrap();
+
+        let errors = doc.validate_interpolation().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(&errors[0], BdlError::ParseError(msg) if msg.contains("'bank'")));
+        assert!(matches!(&errors[1], BdlError::ParseError(msg) if msg.contains("'price gold'")));
+
+        doc.nodes.remove("shop");
+        doc.nodes.remove("bank");
+        assert!(doc.validate_interpolation().is_ok());
+
+        // Escaped markers are literal text, not broken interpolation
+        let content = "@price\nCost: \\${ 5 and \\!{ more\n";
+        let doc = crate::parser::BdlParser::new(content.to_string()).parse().unwrap();
+        assert!(matches!(&doc.nodes["price"].content[..], [BdlContentElement::Text(t)] if t == "Cost: ${ 5 and !{ more"));
+        assert!(doc.validate_interpolation().is_ok());
+    }
+
+    #[test]
+    fn test_longest_path() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["short"], node("end")));
+        start.add_option(option(&["long"], node("forest")));
+        doc.add_node(start).unwrap();
+
+        let mut forest = BdlNode::new("forest".to_string());
+        forest.add_option(option(&["back"], node("start")));
+        forest.add_option(option(&["on"], node("river")));
+        doc.add_node(forest).unwrap();
+
+        let mut river = BdlNode::new("river".to_string());
+        river.add_option(option(&["cross"], node("end")));
+        doc.add_node(river).unwrap();
+
+        let mut end = BdlNode::new("end".to_string());
+        end.add_option(BdlBranchOption {
+            keywords: vec!["quit".to_string()],
+            destination: BdlDestination::Exit,
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(end).unwrap();
+
+        assert_eq!(doc.longest_path("start"), vec!["start", "forest", "river", "end"]);
+        assert!(doc.longest_path("missing").is_empty());
+    }
+
+    #[test]
+    fn test_has_reachable_exit() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["loop"], node("maze")));
+        start.add_option(option(&["door"], node("hall")));
+        doc.add_node(start).unwrap();
+
+        let mut maze = BdlNode::new("maze".to_string());
+        maze.add_option(option(&["left"], node("maze_2")));
+        doc.add_node(maze).unwrap();
+
+        let mut maze_2 = BdlNode::new("maze_2".to_string());
+        maze_2.add_option(option(&["right"], node("maze")));
+        doc.add_node(maze_2).unwrap();
+
+        let mut hall = BdlNode::new("hall".to_string());
+        hall.add_option(BdlBranchOption {
+            keywords: vec!["leave".to_string()],
+            destination: BdlDestination::Exit,
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(hall).unwrap();
+
+        assert!(doc.has_reachable_exit("start"));
+        assert!(doc.validate("start").is_ok());
+
+        assert!(!doc.has_reachable_exit("maze"));
+        let errors = doc.validate("maze").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], BdlError::NodeError(_)));
+    }
+
+    #[test]
+    fn test_validate_references() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["shop"], node("shop")));
+        start.add_option(option(&["begin"], node("strat")));
+        start.add_option(BdlBranchOption {
+            keywords: vec!["learn".to_string()],
+            destination: BdlDestination::FileTransfer {
+                file: "lessons.bdl".to_string(),
+                node: "intro".to_string(),
+            },
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(start).unwrap();
+        doc.add_node(BdlNode::new("shop".to_string())).unwrap();
+
+        match doc.validate_references() {
+            Err(BdlError::NodeError(msg)) => {
+                assert!(msg.contains("'strat'"));
+                assert!(!msg.contains("'shop'"));
+            }
+            other => panic!("Expected NodeError, got {:?}", other),
+        }
+
+        doc.nodes.get_mut("start").unwrap().options.remove(1);
+        assert!(doc.validate_references().is_ok());
+    }
+
+    #[test]
+    fn test_destinations_of_type() {
+        let exit = |keyword: &str| BdlBranchOption {
+            keywords: vec![keyword.to_string()],
+            destination: BdlDestination::Exit,
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        };
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["next"], node("end")));
+        start.add_option(exit("quit"));
+        start.add_option(exit("leave"));
+        doc.add_node(start).unwrap();
+
+        let mut end = BdlNode::new("end".to_string());
+        end.add_option(exit("bye"));
+        doc.add_node(end).unwrap();
+
+        let exits = doc.destinations_of_type(DestinationKind::Exit);
+        assert_eq!(exits, vec![("end", &BdlDestination::Exit), ("start", &BdlDestination::Exit)]);
+
+        let nodes = doc.destinations_of_type(DestinationKind::Node);
+        assert_eq!(nodes, vec![("start", &BdlDestination::Node("end".to_string()))]);
+        assert!(doc.destinations_of_type(DestinationKind::FileTransfer).is_empty());
+    }
+
+    #[test]
+    fn test_unreachable_nodes() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["go"], node("middle")));
+        doc.add_node(start).unwrap();
+
+        let mut middle = BdlNode::new("middle".to_string());
+        middle.add_option(when("lost", option(&["back"], node("start"))));
+        doc.add_node(middle).unwrap();
+
+        let mut orphan = BdlNode::new("orphan".to_string());
+        orphan.add_option(option(&["go"], node("middle")));
+        doc.add_node(orphan).unwrap();
+        doc.add_node(BdlNode::new("attic".to_string())).unwrap();
+
+        assert_eq!(doc.unreachable_nodes("start"), vec!["attic", "orphan"]);
+        assert_eq!(doc.unreachable_nodes("orphan"), vec!["attic"]);
+
+        let mut debug = BdlNode::new("debug".to_string());
+        debug.tags.push("allow-unreachable".to_string());
+        doc.add_node(debug).unwrap();
+        assert_eq!(doc.unreachable_nodes("start"), vec!["attic", "orphan"]);
+    }
+
+    #[test]
+    fn test_find_cycles() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut a = BdlNode::new("a".to_string());
+        a.add_option(option(&["next"], node("b")));
+        doc.add_node(a).unwrap();
+
+        let mut b = BdlNode::new("b".to_string());
+        b.add_option(option(&["back"], node("a")));
+        b.add_option(option(&["wait"], node("b")));
+        b.add_option(option(&["on"], node("c")));
+        doc.add_node(b).unwrap();
+        doc.add_node(BdlNode::new("c".to_string())).unwrap();
+
+        assert_eq!(
+            doc.find_cycles(),
+            vec![vec!["a".to_string(), "b".to_string()], vec!["b".to_string()]]
+        );
+
+        let mut acyclic = BdlDocument::new(None);
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["left"], node("end")));
+        start.add_option(option(&["right"], node("end")));
+        acyclic.add_node(start).unwrap();
+        acyclic.add_node(BdlNode::new("end".to_string())).unwrap();
+
+        assert!(acyclic.find_cycles().is_empty());
+
+        // `@self` is a self-loop like naming the node
+        let mut looping = BdlDocument::new(None);
+        let mut riddle = BdlNode::new("riddle".to_string());
+        riddle.add_option(option(&["again"], BdlDestination::Current));
+        looping.add_node(riddle).unwrap();
+        assert_eq!(looping.find_cycles(), vec![vec!["riddle".to_string()]]);
+        assert_eq!(looping.predecessors("riddle").len(), 1);
+    }
+
+    #[test]
+    fn test_outputs_do_not_depend_on_insertion_order() {
+        let names = ["hub", "attic", "cellar", "yard", "lost", "bank"];
+        let build = |rotation: usize| {
+            let mut doc = BdlDocument::new(None);
+            for i in 0..names.len() {
+                let name = names[(i + rotation) % names.len()];
+                let mut built = BdlNode::new(name.to_string());
+                match name {
+                    "hub" => {
+                        built.add_option(option(&["up"], node("attic")));
+                        built.add_option(option(&["down"], node("cellar")));
+                        built.add_option(option(&["out"], node("yard")));
+                    }
+                    "lost" => built.add_option(option(&["away"], node("bank"))),
+                    _ => built.add_option(option(&["back"], node("hub"))),
+                }
+                doc.add_node(built).unwrap();
+            }
+            doc
+        };
+
+        let expected = build(0);
+        assert_eq!(expected.unreachable_nodes("hub"), vec!["bank", "lost"]);
+        assert_eq!(
+            expected.find_cycles(),
+            vec![vec!["attic", "hub"], vec!["cellar", "hub"], vec!["hub", "yard"]]
+        );
+
+        // Each document gets its own hash seed, so rebuilding in different
+        // orders exercises different `HashMap` iteration orders
+        for rotation in 0..20 {
+            let doc = build(rotation % names.len());
+            assert_eq!(doc.unreachable_nodes("hub"), expected.unreachable_nodes("hub"));
+            assert_eq!(doc.find_cycles(), expected.find_cycles());
+            assert_eq!(
+                doc.destinations_of_type(DestinationKind::Node),
+                expected.destinations_of_type(DestinationKind::Node)
+            );
+        }
+    }
+}
@@ -0,0 +1,1451 @@
+use crate::parser::{ANONYMOUS_NODE_MARKER, AUTO_NODE_PREFIX};
+use crate::{BdlArg, BdlDocument, BdlContentElement, BdlDestination, BdlBranchOption, BdlCondition, BdlError, BdlNode, BdlValue, FormatSpec};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+/// Passage name used for the `Exit` destination in Twee output
+pub const TWEE_EXIT_PASSAGE: &str = "EXIT";
+
+/// Link text used in Twee output for options without keywords
+pub const TWEE_KEYWORDLESS_LABEL: &str = "(continue)";
+
+/// Vertex name used for the `Exit` destination in DOT output
+pub const DOT_EXIT_NODE: &str = "[EXIT]";
+
+/// Key of the metadata and variables fragment returned by `split_by_node`
+///
+/// Node names cannot contain `#`, so this never collides with a node.
+pub const SPLIT_MANIFEST_KEY: &str = "#manifest";
+
+/// A syntax-independent state-machine view of a document, for code generators
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateMachine {
+    /// One state per node, sorted by name
+    pub states: Vec<MachineState>,
+}
+
+/// A single state (node) in a `StateMachine`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineState {
+    pub name: String,
+    /// Side effects performed when the state is entered, in source order
+    pub actions: Vec<MachineAction>,
+    /// Outgoing transitions, one per option keyword (one per all-of option),
+    /// in source order
+    pub transitions: Vec<MachineTransition>,
+}
+
+/// An action performed on entering a state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MachineAction {
+    /// Invoke a host function and bind its results
+    Call {
+        function: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<BdlArg>,
+        result_vars: Vec<String>,
+    },
+}
+
+/// A transition triggered by a single keyword
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MachineTransition {
+    /// The keyword, or for an all-of option its words separated by spaces
+    pub keyword: String,
+    pub guard: Option<BdlCondition>,
+    pub target: BdlDestination,
+    /// Whether every word of `keyword` must appear in the input
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub match_all: bool,
+}
+
+impl BdlDocument {
+    /// Render a depth-first, human-readable walk of every branch from `entry`
+    ///
+    /// Each node's text is printed once per path, with the branches under it
+    /// indented by option. A node already on the current path is printed as a
+    /// back-reference instead of being expanded again, so cycles terminate.
+    pub fn to_transcript(&self, entry: &str) -> String {
+        let mut out = String::new();
+        let mut path = HashSet::new();
+        self.write_transcript_node(entry, 0, &mut path, &mut out);
+        out
+    }
+
+    fn write_transcript_node<'a>(&'a self, name: &'a str, depth: usize, path: &mut HashSet<&'a str>, out: &mut String) {
+        let indent = "  ".repeat(depth);
+
+        let Some(node) = self.nodes.get(name) else {
+            out.push_str(&format!("{}@{} (missing)\n", indent, name));
+            return;
+        };
+        if path.contains(name) {
+            out.push_str(&format!("{}@{} (see above)\n", indent, name));
+            return;
+        }
+
+        out.push_str(&format!("{}@{}\n", indent, name));
+        for line in content_to_text(&node.content).lines() {
+            out.push_str(&format!("{}  {}\n", indent, line));
+        }
+
+        path.insert(name);
+        for option in &node.options {
+            out.push_str(&format!("{}  {}\n", indent, option_label(option)));
+            match &option.destination {
+                BdlDestination::Node(target) => self.write_transcript_node(target, depth + 2, path, out),
+                destination => {
+                    out.push_str(&format!("{}    -> {}\n", indent, destination_to_text(destination)));
+                }
+            }
+        }
+        path.remove(name);
+    }
+
+    /// Build a normalized state machine (states, transitions and actions)
+    pub fn to_state_machine(&self) -> StateMachine {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let states = names
+            .into_iter()
+            .map(|name| {
+                let node = &self.nodes[name];
+
+                let actions = node
+                    .content
+                    .iter()
+                    .filter_map(|element| match element {
+                        BdlContentElement::FunctionCall { name, args, result_vars } => Some(MachineAction::Call {
+                            function: name.clone(),
+                            args: args.clone(),
+                            result_vars: result_vars.clone(),
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+
+                let transitions = node
+                    .options
+                    .iter()
+                    .flat_map(|option| {
+                        // An all-of option is one transition on its combined words
+                        let keywords = if option.match_all {
+                            vec![option.keywords.join(" ")]
+                        } else {
+                            option.keywords.clone()
+                        };
+                        keywords.into_iter().map(|keyword| MachineTransition {
+                            keyword,
+                            guard: option.condition.clone(),
+                            target: option.destination.clone(),
+                            match_all: option.match_all,
+                        })
+                    })
+                    .collect();
+
+                MachineState {
+                    name: name.clone(),
+                    actions,
+                    transitions,
+                }
+            })
+            .collect();
+
+        StateMachine { states }
+    }
+
+    /// Export every option as a CSV row for spreadsheet review
+    ///
+    /// Columns are `node,keywords,destination,condition,text`. Nodes are listed
+    /// by name; a node without options still gets one row so dead ends are
+    /// visible. The text column is the node's content collapsed to one line.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("node,keywords,destination,condition,text\n");
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        for name in names {
+            let node = &self.nodes[name];
+            let text = content_to_text(&node.content).split_whitespace().collect::<Vec<_>>().join(" ");
+
+            if node.options.is_empty() {
+                out.push_str(&csv_row(&[name, "", "", "", &text]));
+            }
+            for option in &node.options {
+                let condition = option.condition.as_ref().map(|c| c.to_string()).unwrap_or_default();
+                out.push_str(&csv_row(&[
+                    name,
+                    &option.keywords.join("; "),
+                    &destination_to_text(&option.destination),
+                    &condition,
+                    &text,
+                ]));
+            }
+        }
+
+        out
+    }
+
+    /// Render the document as `.bdl` source that parses back to an equivalent document
+    ///
+    /// Metadata comes first in a fixed key order, then the variable blocks
+    /// with keys sorted, then nodes sorted by name (the document does not
+    /// keep source order). Options keep their order within each node.
+    /// Unnamed `__auto_N` nodes are written last as a `---` chain, so parsing
+    /// them back needs `ParserOptions::auto_name_anonymous`. Blank lines
+    /// between paragraphs are kept if the document was parsed with
+    /// `ParserOptions::preserve_paragraph_breaks`.
+    pub fn to_bdl_string(&self) -> String {
+        self.write_bdl(false)
+    }
+
+    /// Render the document as BDL in a canonical, diff-friendly form
+    ///
+    /// Same layout as `to_bdl_string`, but options within a node are sorted
+    /// by keywords, then match mode, condition and destination, so the output is meant for review rather than for
+    /// running: the relative order of overlapping options is not preserved.
+    /// Trailing whitespace is stripped from every line.
+    pub fn to_canonical_bdl(&self) -> String {
+        self.write_bdl(true)
+    }
+
+    /// Split the document into one `.bdl` fragment per node, keyed by node name
+    ///
+    /// Metadata and variable blocks go into a separate fragment under
+    /// `SPLIT_MANIFEST_KEY`. Each node fragment holds just that node, written
+    /// as in `to_bdl_string`; unnamed `__auto_N` nodes keep their generated
+    /// names and explicit fallthrough options. Fragments that call functions
+    /// or transfer to other files need the manifest's `Required` list when
+    /// parsed on their own.
+    pub fn split_by_node(&self) -> HashMap<String, String> {
+        let mut manifest = String::new();
+        self.write_preamble(&mut manifest);
+
+        let mut fragments = HashMap::from([(SPLIT_MANIFEST_KEY.to_string(), manifest)]);
+        for (name, node) in &self.nodes {
+            let mut fragment = String::new();
+            write_node(&mut fragment, &node_header(name, node), &node.content, &node.options, false);
+            fragments.insert(name.clone(), fragment);
+        }
+        fragments
+    }
+
+    /// Write the metadata comments and variable blocks that precede the nodes
+    fn write_preamble(&self, out: &mut String) {
+        let metadata = &self.metadata;
+        if let Some(version) = metadata.format_version {
+            out.push_str(&format!("#!bdl {}\n", version));
+        }
+        for (key, value) in [
+            ("Topic", &metadata.topic),
+            ("Description", &metadata.description),
+            ("Author", &metadata.author),
+            ("Version", &metadata.version),
+        ] {
+            if let Some(value) = value {
+                out.push_str(&format!("# {}: {}\n", key, value));
+            }
+        }
+        if let Some(required) = &metadata.required {
+            out.push_str(&format!("# Required: {}\n", required.join(", ")));
+        }
+        if let Some(changelog) = &metadata.changelog {
+            out.push_str("# Changelog:\n");
+            for entry in changelog {
+                out.push_str(&format!("# - {}\n", entry));
+            }
+        }
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        if let Some(global_vars) = &self.global_vars {
+            write_variable_block(out, "$global_vars", global_vars);
+        }
+        if !self.local_vars.is_empty() {
+            write_variable_block(out, "$local_vars", &self.local_vars);
+        }
+    }
+
+
+    fn write_bdl(&self, sort_options: bool) -> String {
+        let mut out = String::new();
+        self.write_preamble(&mut out);
+
+        // `__auto_0`, `__auto_1`, ... are written back as the `---` chain they
+        // were parsed from, after the named nodes so none of them is cut short
+        let chain: Vec<&BdlNode> = (0..)
+            .map_while(|index| self.nodes.get(&format!("{}{}", AUTO_NODE_PREFIX, index)))
+            .collect();
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        for name in names {
+            let node = &self.nodes[name];
+            if !chain.iter().any(|linked| linked.name == node.name) {
+                write_node(&mut out, &node_header(name, node), &node.content, &node.options, sort_options);
+            }
+        }
+
+        for (index, node) in chain.iter().enumerate() {
+            // The parser adds the fallthrough to the next unnamed node itself
+            let falls_through = matches!(
+                &node.options[..],
+                [BdlBranchOption { keywords, condition: None, destination: BdlDestination::Node(target), match_all: false, doc: None, order: None, enabled: None }]
+                    if keywords.is_empty() && chain.get(index + 1).is_some_and(|next| &next.name == target)
+            );
+            let options = if falls_through { &[][..] } else { &node.options[..] };
+            write_node(&mut out, ANONYMOUS_NODE_MARKER, &node.content, options, sort_options);
+        }
+
+        out
+    }
+
+    /// Extract the spoken lines of every node, e.g. for voice recording
+    ///
+    /// Each node with text gets a `## name` heading followed by its lines.
+    /// Variables appear as `<name>` placeholders and inline conditionals as
+    /// `<then|otherwise>`; function calls, directives, data blocks and
+    /// options are left out. Nodes are listed by name.
+    pub fn to_script(&self) -> String {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut sections = Vec::new();
+        for name in names {
+            let mut text = String::new();
+            for element in &self.nodes[name].content {
+                match element {
+                    BdlContentElement::Text(t) => text.push_str(t),
+                    BdlContentElement::Variable(var) | BdlContentElement::FormattedVariable { name: var, .. } => {
+                        text.push_str(&format!("<{}>", var))
+                    }
+                    BdlContentElement::Conditional { then, otherwise, .. } => {
+                        text.push_str(&format!("<{}|{}>", then, otherwise))
+                    }
+                    _ => {}
+                }
+            }
+
+            let lines: Vec<&str> = text.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+            if !lines.is_empty() {
+                sections.push(format!("## {}\n{}\n", name, lines.join("\n")));
+            }
+        }
+
+        sections.join("\n")
+    }
+
+    /// Export the document as a Twee 3 story that can be imported into Twine
+    ///
+    /// Mapping:
+    /// - `Topic` becomes the `StoryTitle` passage; `start` (if present) and the
+    ///   remaining metadata go into the `StoryData` passage
+    /// - each node becomes a passage with its content as text
+    /// - each option becomes a `[[keywords->target]]` link, keywords joined by `, `;
+    ///   an option without keywords (`{exit}`, `?{cond} -> @x` or a `---`
+    ///   fallthrough) is labelled `TWEE_KEYWORDLESS_LABEL`
+    /// - a file transfer links to a passage named `file.bdl:node`, and a
+    ///   computed destination to one named `!{function}`; both are left for
+    ///   the importing project to provide
+    /// - `Exit` links to an empty `EXIT` passage
+    ///
+    /// Option conditions have no story-format-independent equivalent and are
+    /// not exported.
+    pub fn to_twee(&self) -> String {
+        let mut out = String::new();
+
+        if let Some(topic) = &self.metadata.topic {
+            out.push_str(&format!(":: StoryTitle\n{}\n\n", topic));
+        }
+
+        let mut story_data = serde_json::Map::new();
+        if self.nodes.contains_key("start") {
+            story_data.insert("start".to_string(), "start".into());
+        }
+        for (key, value) in [
+            ("description", &self.metadata.description),
+            ("author", &self.metadata.author),
+            ("version", &self.metadata.version),
+        ] {
+            if let Some(value) = value {
+                story_data.insert(key.to_string(), value.clone().into());
+            }
+        }
+        let story_data = serde_json::to_string_pretty(&story_data).unwrap_or_default();
+        out.push_str(&format!(":: StoryData\n{}\n\n", story_data));
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut uses_exit = false;
+        for name in names {
+            let node = &self.nodes[name];
+            out.push_str(&format!(":: {}\n", name));
+
+            let text = content_to_text(&node.content);
+            if !text.is_empty() {
+                out.push_str(&format!("{}\n", text));
+            }
+
+            for option in &node.options {
+                let target = match &option.destination {
+                    BdlDestination::Node(target) => target.clone(),
+                    BdlDestination::FileTransfer { file, node } => format!("{}:{}", file, node),
+                    BdlDestination::Exit => {
+                        uses_exit = true;
+                        TWEE_EXIT_PASSAGE.to_string()
+                    }
+                    BdlDestination::Current => name.clone(),
+                    BdlDestination::Computed { function } => format!("!{{{}}}", function),
+                };
+                let label = if option.keywords.is_empty() {
+                    TWEE_KEYWORDLESS_LABEL.to_string()
+                } else {
+                    option.keywords.join(", ")
+                };
+                out.push_str(&format!("[[{}->{}]]\n", label, target));
+            }
+            out.push('\n');
+        }
+
+        if uses_exit {
+            out.push_str(&format!(":: {}\n\n", TWEE_EXIT_PASSAGE));
+        }
+
+        out
+    }
+
+    /// Render the dialogue graph as a Graphviz `digraph`
+    ///
+    /// Every node is a vertex and every option an edge labelled with its
+    /// keywords; options with a condition are drawn dashed. File transfers
+    /// and computed destinations point to box-shaped external vertices named
+    /// `file.bdl:node` and `!{function}`, and all `Exit` options share a
+    /// single `[EXIT]` sink. Vertices and edges are written in node name
+    /// order.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph bdl {\n");
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+        for name in &names {
+            out.push_str(&format!("    {};\n", dot_id(name)));
+        }
+
+        let mut external = BTreeSet::new();
+        let mut uses_exit = false;
+        for name in names {
+            for option in &self.nodes[name].options {
+                let target = match &option.destination {
+                    BdlDestination::Node(target) => target.clone(),
+                    BdlDestination::FileTransfer { file, node } => {
+                        let target = format!("{}:{}", file, node);
+                        external.insert(target.clone());
+                        target
+                    }
+                    BdlDestination::Exit => {
+                        uses_exit = true;
+                        DOT_EXIT_NODE.to_string()
+                    }
+                    BdlDestination::Current => name.clone(),
+                    BdlDestination::Computed { function } => {
+                        let target = format!("!{{{}}}", function);
+                        external.insert(target.clone());
+                        target
+                    }
+                };
+
+                let mut attributes = format!("label={}", dot_id(&option.keywords.join(", ")));
+                if option.condition.is_some() {
+                    attributes.push_str(", style=dashed");
+                }
+                out.push_str(&format!("    {} -> {} [{}];\n", dot_id(name), dot_id(&target), attributes));
+            }
+        }
+
+        for target in external {
+            out.push_str(&format!("    {} [shape=box, style=dashed];\n", dot_id(&target)));
+        }
+        if uses_exit {
+            out.push_str(&format!("    {} [shape=doublecircle];\n", dot_id(DOT_EXIT_NODE)));
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl BdlDocument {
+    /// Import a Twee 3 story, the inverse of `to_twee`
+    ///
+    /// Passages become nodes and `[[...]]` links become options. Links written
+    /// as `[[text->target]]`, `[[target<-text]]`, `[[text|target]]` or
+    /// `[[target]]` are all accepted; the link text is split on commas into
+    /// keywords. Empty link text or `TWEE_KEYWORDLESS_LABEL` gives an option
+    /// without keywords. Passage names are converted to valid node names by replacing
+    /// any character other than letters, digits and `_` with `_`. Story format
+    /// macros are not interpreted and are kept as raw text, and passages
+    /// tagged `script` or `stylesheet` are skipped.
+    pub fn from_twee(s: &str) -> Result<BdlDocument, BdlError> {
+        let mut doc = BdlDocument::new(None);
+
+        for (header, body) in split_passages(s)? {
+            let (name, tags) = parse_passage_header(header)?;
+
+            if name == "StoryTitle" {
+                doc.metadata.topic = Some(body.trim().to_string());
+                continue;
+            }
+            if name == "StoryData" {
+                apply_story_data(&mut doc, body)?;
+                continue;
+            }
+            if tags.iter().any(|tag| tag == "script" || tag == "stylesheet") {
+                continue;
+            }
+            if name == TWEE_EXIT_PASSAGE && body.trim().is_empty() {
+                continue;
+            }
+
+            let mut node = BdlNode::new(twee_node_name(name));
+            let mut text = Vec::new();
+            for line in body.lines() {
+                let (line_text, links) = extract_twee_links(line)?;
+                for (label, target) in links {
+                    let keywords = if label.is_empty() || label == TWEE_KEYWORDLESS_LABEL {
+                        Vec::new()
+                    } else {
+                        label.split(',').map(|k| k.trim().to_string()).collect()
+                    };
+                    node.add_option(BdlBranchOption {
+                        keywords,
+                        destination: twee_destination(&target),
+                        condition: None,
+                        match_all: false,
+                        doc: None,
+                        order: None,
+                        enabled: None,
+                    });
+                }
+                if let Some(line_text) = line_text {
+                    text.push(line_text);
+                }
+            }
+
+            let text = text.join("\n");
+            let text = text.trim();
+            if !text.is_empty() {
+                node.add_content(BdlContentElement::Text(text.to_string()));
+            }
+            doc.add_node(node)?;
+        }
+
+        Ok(doc)
+    }
+}
+
+/// Split a Twee source into `(header, body)` pairs, one per `::` passage
+fn split_passages(s: &str) -> Result<Vec<(&str, &str)>, BdlError> {
+    let mut passages = Vec::new();
+    let mut current: Option<(&str, usize)> = None;
+    let mut offset = 0;
+
+    for line in s.split_inclusive('\n') {
+        if let Some(header) = line.strip_prefix("::") {
+            if let Some((header, start)) = current.take() {
+                passages.push((header, &s[start..offset]));
+            }
+            current = Some((header.trim(), offset + line.len()));
+        } else if current.is_none() && !line.trim().is_empty() {
+            return Err(BdlError::ParseError(format!("Text outside of a passage: {}", line.trim()).into()));
+        }
+        offset += line.len();
+    }
+
+    if let Some((header, start)) = current {
+        passages.push((header, &s[start..]));
+    }
+
+    Ok(passages)
+}
+
+/// Split a passage header into its name and tags, ignoring any `{...}` metadata
+fn parse_passage_header(header: &str) -> Result<(&str, Vec<String>), BdlError> {
+    let header = match header.find('{') {
+        Some(index) => &header[..index],
+        None => header,
+    };
+
+    let (name, tags) = match header.split_once('[') {
+        Some((name, tags)) => {
+            let tags = tags.trim_end().trim_end_matches(']');
+            (name.trim(), tags.split_whitespace().map(str::to_string).collect())
+        }
+        None => (header.trim(), Vec::new()),
+    };
+
+    if name.is_empty() {
+        return Err(BdlError::ParseError("Passage header without a name".into()));
+    }
+    Ok((name, tags))
+}
+
+/// Copy known fields from the `StoryData` JSON into the document metadata
+fn apply_story_data(doc: &mut BdlDocument, body: &str) -> Result<(), BdlError> {
+    let data: serde_json::Value = serde_json::from_str(body.trim())
+        .map_err(|e| BdlError::ParseError(format!("Invalid StoryData: {}", e).into()))?;
+
+    let field = |key: &str| data.get(key).and_then(|v| v.as_str()).map(str::to_string);
+    doc.metadata.description = field("description");
+    doc.metadata.author = field("author");
+    doc.metadata.version = field("version");
+    Ok(())
+}
+
+/// A Twee link's `(label, target)`
+type TweeLink = (String, String);
+
+/// Pull `[[...]]` links out of a line, returning each link's `(label, target)`
+///
+/// The returned text has each link replaced by its label, or is `None` when
+/// the line held nothing but links.
+fn extract_twee_links(line: &str) -> Result<(Option<String>, Vec<TweeLink>), BdlError> {
+    let mut text = String::new();
+    let mut has_plain_text = false;
+    let mut links = Vec::new();
+    let mut rest = line;
+
+    while let Some(start) = rest.find("[[") {
+        let end = rest[start..]
+            .find("]]")
+            .map(|end| start + end)
+            .ok_or_else(|| BdlError::ParseError(format!("Unterminated link: {}", line.trim()).into()))?;
+
+        let link = &rest[start + 2..end];
+        let (label, target) = if let Some((label, target)) = link.split_once("->") {
+            (label, target)
+        } else if let Some((target, label)) = link.split_once("<-") {
+            (label, target)
+        } else if let Some((label, target)) = link.split_once('|') {
+            (label, target)
+        } else {
+            (link, link)
+        };
+
+        has_plain_text |= !rest[..start].trim().is_empty();
+        text.push_str(&rest[..start]);
+        text.push_str(label.trim());
+        links.push((label.trim().to_string(), target.trim().to_string()));
+        rest = &rest[end + 2..];
+    }
+    has_plain_text |= !rest.trim().is_empty();
+    text.push_str(rest);
+
+    if links.is_empty() || has_plain_text {
+        Ok((Some(text), links))
+    } else {
+        Ok((None, links))
+    }
+}
+
+/// Map a Twee link target back to a destination, following the `to_twee` conventions
+fn twee_destination(target: &str) -> BdlDestination {
+    if target == TWEE_EXIT_PASSAGE {
+        return BdlDestination::Exit;
+    }
+    if let Some(function) = target.strip_prefix("!{").and_then(|rest| rest.strip_suffix('}')) {
+        return BdlDestination::Computed { function: function.to_string() };
+    }
+    match target.split_once(':') {
+        Some((file, node)) if file.ends_with(".bdl") => BdlDestination::FileTransfer {
+            file: file.to_string(),
+            node: node.to_string(),
+        },
+        _ => BdlDestination::Node(twee_node_name(target)),
+    }
+}
+
+/// Convert a passage name into a valid node name
+fn twee_node_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Quote a string as a DOT identifier
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Render a destination the way it is written after `->`
+pub(crate) fn destination_to_text(destination: &BdlDestination) -> String {
+    match destination {
+        BdlDestination::Node(name) => format!("@{}", name),
+        BdlDestination::FileTransfer { file, node } => format!("[{}:{}]", file, node),
+        BdlDestination::Exit => "exit".to_string(),
+        BdlDestination::Current => "@self".to_string(),
+        BdlDestination::Computed { function } => format!("!{{{}}}", function),
+    }
+}
+
+/// Join fields into one CSV line, quoting any that need it
+fn csv_row(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    format!("{}\n", fields.join(","))
+}
+
+/// Render node content back to its source-like text form
+pub(crate) fn content_to_text(content: &[BdlContentElement]) -> String {
+    let mut text = String::new();
+    for element in content {
+        match element {
+            BdlContentElement::Text(t) => text.push_str(t),
+            BdlContentElement::Variable(name) => text.push_str(&format!("${{{}}}", name)),
+            BdlContentElement::FormattedVariable { name, spec } => text.push_str(&format!("${{{}:{}}}", name, spec)),
+            BdlContentElement::Conditional { condition, then, otherwise } => text.push_str(&format!(
+                "${{{} ? {} : {}}}",
+                condition,
+                value_to_bdl(&BdlValue::String(then.clone())),
+                value_to_bdl(&BdlValue::String(otherwise.clone()))
+            )),
+            BdlContentElement::FunctionCall { name, args, .. } => text.push_str(&format!("!{{{}}}", call_to_bdl(name, args))),
+            BdlContentElement::Custom { tag, data } => text.push_str(&format!("%{} {}", tag, data)),
+            BdlContentElement::Data { mime, body } => text.push_str(&format!("%data {} {{{}}}", mime, body)),
+        }
+    }
+    text
+}
+
+/// Write a `$block: { ... }` variable block with keys in sorted order
+fn write_variable_block(out: &mut String, block: &str, vars: &HashMap<String, BdlValue>) {
+    let mut keys: Vec<&String> = vars.keys().collect();
+    keys.sort();
+
+    let entries: Vec<String> = keys
+        .into_iter()
+        .map(|key| format!("    {}: {}", key, value_to_bdl(&vars[key])).trim_end().to_string())
+        .collect();
+    out.push_str(&format!("{}: {{\n{}\n}}\n\n", block, entries.join(",\n")));
+}
+
+/// Renders the inside of `?{...}`, e.g. `score >= 100`
+impl fmt::Display for BdlCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.comparison {
+            Some(comparison) => write!(
+                f,
+                "{} {} {}",
+                self.variable,
+                comparison.operator.symbol(),
+                value_to_bdl(&comparison.value)
+            ),
+            None => f.write_str(&self.variable),
+        }
+    }
+}
+
+/// Renders the `spec` of `${var:spec}`, e.g. `02` or `.2`
+impl fmt::Display for FormatSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.zero_pad {
+            f.write_str("0")?;
+        }
+        if let Some(width) = self.width {
+            write!(f, "{}", width)?;
+        }
+        if let Some(precision) = self.precision {
+            write!(f, ".{}", precision)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders a function argument, with variables always in the `${var}` form
+impl fmt::Display for BdlArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BdlArg::Value(value) => f.write_str(&value_to_bdl(value)),
+            BdlArg::Variable(name) => write!(f, "${{{}}}", name),
+        }
+    }
+}
+
+/// Render a function name with its argument list, if it has arguments
+fn call_to_bdl(name: &str, args: &[BdlArg]) -> String {
+    if args.is_empty() {
+        return name.to_string();
+    }
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    format!("{}({})", name, args.join(", "))
+}
+
+/// Render a value the way it is written in a variable block
+fn value_to_bdl(value: &BdlValue) -> String {
+    match value {
+        BdlValue::String(s) => {
+            let escaped = s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t");
+            format!("\"{}\"", escaped)
+        }
+        BdlValue::Integer(n) => n.to_string(),
+        // Debug keeps the `.0` that tells a float from an integer
+        BdlValue::Number(n) => format!("{:?}", n),
+        BdlValue::Boolean(b) => b.to_string(),
+        BdlValue::List(items) => {
+            let items: Vec<String> = items.iter().map(value_to_bdl).collect();
+            format!("[{}]", items.join(", "))
+        }
+        BdlValue::Map(entries) => {
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort();
+            let entries: Vec<String> = keys
+                .into_iter()
+                .map(|key| format!("{}: {}", map_key_to_bdl(key), value_to_bdl(&entries[key])))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        BdlValue::Empty => String::new(),
+    }
+}
+
+/// Map keys are written bare when they are simple words, quoted otherwise
+fn map_key_to_bdl(key: &str) -> String {
+    if key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        key.to_string()
+    } else {
+        value_to_bdl(&BdlValue::String(key.to_string()))
+    }
+}
+
+/// The `@name #tag ...` header line of a named node
+fn node_header(name: &str, node: &BdlNode) -> String {
+    let tags: String = node.tags.iter().map(|tag| format!(" #{}", tag)).collect();
+    format!("@{}{}", name, tags)
+}
+
+/// Write one node block under `header`, followed by a blank line
+fn write_node(out: &mut String, header: &str, content: &[BdlContentElement], options: &[BdlBranchOption], sort_options: bool) {
+    out.push_str(header);
+    out.push('\n');
+
+    let text = content_to_bdl(content);
+    for line in text.lines() {
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+
+    let mut options: Vec<&BdlBranchOption> = options.iter().collect();
+    if sort_options {
+        // Sort on everything that is written, so equal keywords don't fall
+        // back to input order
+        options.sort_by_cached_key(|option| {
+            (
+                option.keywords.clone(),
+                option.match_all,
+                option.condition.as_ref().map(ToString::to_string),
+                destination_to_text(&option.destination),
+                option.order,
+                option.doc.clone(),
+                option.enabled.as_ref().map(ToString::to_string),
+            )
+        });
+    }
+    for option in options {
+        out.push_str(&option_to_bdl(option));
+        out.push('\n');
+    }
+    out.push('\n');
+}
+
+/// Render node content as BDL source, keeping function result bindings
+///
+/// Directives and data blocks are put on lines of their own.
+fn content_to_bdl(content: &[BdlContentElement]) -> String {
+    let mut text = String::new();
+    for element in content {
+        match element {
+            BdlContentElement::FunctionCall { name, args, result_vars } if !result_vars.is_empty() => {
+                text.push_str(&format!("!{{{} -> {}}}", call_to_bdl(name, args), result_vars.join(", ")));
+            }
+            BdlContentElement::Custom { .. } | BdlContentElement::Data { .. } => {
+                if !text.is_empty() && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text.push_str(&content_to_text(std::slice::from_ref(element)));
+                text.push('\n');
+            }
+            BdlContentElement::Text(t) => {
+                text.push_str(&t.replace('\\', "\\\\").replace("${", "\\${").replace("!{", "\\!{"));
+            }
+            other => text.push_str(&content_to_text(std::slice::from_ref(other))),
+        }
+    }
+    text
+}
+
+/// Render an option as a `?{cond} {keywords} -> destination` line, with any `/// doc`
+fn option_to_bdl(option: &BdlBranchOption) -> String {
+    let mut line = option_body_to_bdl(option);
+    if let Some(doc) = &option.doc {
+        line.push_str(&format!("  /// {}", doc));
+    }
+    line
+}
+
+/// The option line without its doc comment
+fn option_body_to_bdl(option: &BdlBranchOption) -> String {
+    if option.keywords.is_empty() && option.condition.is_none() && option.destination == BdlDestination::Exit {
+        return "{exit}".to_string();
+    }
+
+    let mut line = String::new();
+    if let Some(condition) = &option.condition {
+        line.push_str(&format!("?{{{}}} ", condition));
+    }
+    if let Some(enabled) = &option.enabled {
+        line.push_str(&format!("~{{{}}} ", enabled));
+    }
+    let order = option.order.map(|order| format!("@{}", order)).unwrap_or_default();
+    if option.match_all {
+        let keywords: Vec<String> = option.keywords.iter().map(|k| format!("+{}", k)).collect();
+        line.push_str(&format!("{{{}}}{} ", keywords.join(" "), order));
+    } else if !option.keywords.is_empty() || option.condition.is_none() {
+        line.push_str(&format!("{{{}}}{} ", option.keywords.join(", "), order));
+    }
+    line.push_str(&format!("-> {}", destination_to_text(&option.destination)));
+    line
+}
+
+/// Short label for an option: its keywords plus any condition
+fn option_label(option: &BdlBranchOption) -> String {
+    let keywords = option.keywords.join(", ");
+    match &option.condition {
+        Some(condition) => format!("[{}] ?{{{}}}", keywords, condition),
+        None => format!("[{}]", keywords),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{node, option, when};
+    use crate::BdlNode;
+
+    #[test]
+    fn test_transcript_contains_both_branches() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_content(BdlContentElement::Text("Pick a door".to_string()));
+        start.add_option(option(&["left"], BdlDestination::Node("left_room".to_string())));
+        start.add_option(option(&["right"], BdlDestination::Node("right_room".to_string())));
+        doc.add_node(start).unwrap();
+
+        let mut left = BdlNode::new("left_room".to_string());
+        left.add_content(BdlContentElement::Text("A dragon!".to_string()));
+        left.add_option(option(&["back"], BdlDestination::Node("start".to_string())));
+        doc.add_node(left).unwrap();
+
+        let mut right = BdlNode::new("right_room".to_string());
+        right.add_content(BdlContentElement::Text("Treasure!".to_string()));
+        right.add_option(option(&["quit"], BdlDestination::Exit));
+        doc.add_node(right).unwrap();
+
+        let transcript = doc.to_transcript("start");
+        assert!(transcript.starts_with("@start\n  Pick a door\n"));
+        assert!(transcript.contains("  [left]\n    @left_room\n      A dragon!\n"));
+        assert!(transcript.contains("  [right]\n    @right_room\n      Treasure!\n"));
+
+        // The cycle back to start is cut off rather than expanded forever
+        assert!(transcript.contains("@start (see above)"));
+        assert!(transcript.contains("-> exit"));
+    }
+
+    #[test]
+    fn test_csv_header_and_option_row() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_content(BdlContentElement::Text("Hello, traveller".to_string()));
+        start.add_option(BdlBranchOption {
+            keywords: vec!["yes".to_string(), "ok".to_string()],
+            destination: BdlDestination::Node("accept".to_string()),
+            condition: Some(crate::BdlCondition {
+                variable: "has_key".to_string(),
+                comparison: None,
+            }),
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(start).unwrap();
+        doc.add_node(BdlNode::new("accept".to_string())).unwrap();
+
+        let csv = doc.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "node,keywords,destination,condition,text");
+        assert_eq!(lines[1], "accept,,,,");
+        assert_eq!(lines[2], "start,yes; ok,@accept,has_key,\"Hello, traveller\"");
+    }
+
+    #[test]
+    fn test_state_machine_transitions() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_content(BdlContentElement::FunctionCall {
+            name: "rollDice".to_string(),
+            args: Vec::new(),
+            result_vars: vec!["roll".to_string()],
+        });
+        start.add_option(BdlBranchOption {
+            keywords: vec!["go".to_string(), "walk".to_string()],
+            destination: BdlDestination::Node("road".to_string()),
+            condition: Some(BdlCondition {
+                variable: "awake".to_string(),
+                comparison: None,
+            }),
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(start).unwrap();
+
+        let mut road = BdlNode::new("road".to_string());
+        road.add_option(option(&["quit"], BdlDestination::Exit));
+        road.add_option(BdlBranchOption {
+            match_all: true,
+            ..option(&["open", "door"], node("start"))
+        });
+        doc.add_node(road).unwrap();
+
+        let machine = doc.to_state_machine();
+        let names: Vec<&str> = machine.states.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["road", "start"]);
+
+        let start = &machine.states[1];
+        assert_eq!(start.transitions.len(), 2);
+        assert_eq!(start.transitions[0].keyword, "go");
+        assert_eq!(start.transitions[1].keyword, "walk");
+        assert!(start.transitions.iter().all(|t| {
+            matches!(&t.target, BdlDestination::Node(n) if n == "road")
+                && t.guard.as_ref().is_some_and(|g| g.variable == "awake")
+        }));
+        assert!(matches!(
+            &start.actions[..],
+            [MachineAction::Call { function, .. }] if function == "rollDice"
+        ));
+
+        let road = &machine.states[0];
+        assert_eq!(road.transitions[0].keyword, "quit");
+        assert!(!road.transitions[0].match_all);
+        assert_eq!(road.transitions[1].keyword, "open door");
+        assert!(road.transitions[1].match_all);
+        assert!(matches!(road.transitions[0].target, BdlDestination::Exit));
+    }
+
+    #[test]
+    fn test_twee_passage_and_links() {
+        let mut doc = BdlDocument::new(Some(crate::BdlMetadata {
+            topic: Some("Test Story".to_string()),
+            author: Some("Test Author".to_string()),
+            ..Default::default()
+        }));
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_content(BdlContentElement::Text("Hello ".to_string()));
+        start.add_content(BdlContentElement::Variable("name".to_string()));
+        start.add_option(BdlBranchOption {
+            keywords: vec!["yes".to_string(), "ok".to_string()],
+            destination: BdlDestination::Node("accept".to_string()),
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        start.add_option(option(&["back"], BdlDestination::FileTransfer {
+            file: "main.bdl".to_string(),
+            node: "intro".to_string(),
+        }));
+        doc.add_node(start).unwrap();
+
+        let mut accept = BdlNode::new("accept".to_string());
+        accept.add_option(option(&["quit"], BdlDestination::Exit));
+        accept.add_option(option(&[], BdlDestination::Exit));
+        doc.add_node(accept).unwrap();
+
+        let twee = doc.to_twee();
+        assert!(twee.starts_with(":: StoryTitle\nTest Story\n\n:: StoryData\n"));
+        assert!(twee.contains("\"start\": \"start\""));
+        assert!(twee.contains("\"author\": \"Test Author\""));
+        assert!(twee.contains(":: start\nHello ${name}\n[[yes, ok->accept]]\n[[back->main.bdl:intro]]\n"));
+        assert!(twee.contains(":: accept\n[[quit->EXIT]]\n[[(continue)->EXIT]]\n"));
+        assert!(twee.ends_with(":: EXIT\n\n"));
+    }
+
+    #[test]
+    fn test_from_twee_story() {
+        let twee = r#":: StoryTitle
+Imported Story
+
+:: StoryData
+{
+  "ifid": "D674C58C-DEFA-4F70-B7A2-27742230C0FC",
+  "start": "Start",
+  "author": "Someone"
+}
+
+:: Start [intro]
+You stand at a fork.
+(set: $visited to true)
+Take the [[left->Left Path]] or the [[right|Right Path]].
+[[quit->EXIT]]
+
+:: Left Path
+A dead end.
+[[back<-Start]]
+
+:: Right Path
+[[Start]]
+
+:: EXIT
+
+:: Scripts [script]
+window.foo = 1;
+"#;
+        let doc = BdlDocument::from_twee(twee).unwrap();
+        assert_eq!(doc.metadata.topic, Some("Imported Story".to_string()));
+        assert_eq!(doc.metadata.author, Some("Someone".to_string()));
+        assert_eq!(doc.nodes.len(), 3);
+
+        let start = &doc.nodes["Start"];
+        assert_eq!(start.options.len(), 3);
+        assert!(matches!(&start.options[0].destination, BdlDestination::Node(n) if n == "Left_Path"));
+        assert_eq!(start.options[1].keywords, vec!["right"]);
+        assert!(matches!(start.options[2].destination, BdlDestination::Exit));
+        match &start.content[0] {
+            BdlContentElement::Text(text) => {
+                // Macros are preserved and inline link text is kept
+                assert!(text.contains("(set: $visited to true)"));
+                assert!(text.contains("Take the left or the right."));
+                assert!(!text.contains("quit"));
+            }
+            _ => panic!("Expected Text content"),
+        }
+
+        assert_eq!(doc.nodes["Left_Path"].options.len(), 1);
+        assert_eq!(doc.nodes["Right_Path"].options[0].keywords, vec!["Start"]);
+        assert!(doc.nodes["Right_Path"].content.is_empty());
+    }
+
+    #[test]
+    fn test_twee_round_trip_counts() {
+        let mut doc = BdlDocument::new(None);
+        let mut start = BdlNode::new("start".to_string());
+        start.add_content(BdlContentElement::Text("Hi".to_string()));
+        start.add_option(option(&["go"], BdlDestination::Node("end".to_string())));
+        start.add_option(option(&["home"], BdlDestination::FileTransfer {
+            file: "main.bdl".to_string(),
+            node: "intro".to_string(),
+        }));
+        doc.add_node(start).unwrap();
+        let mut end = BdlNode::new("end".to_string());
+        end.add_option(option(&["quit"], BdlDestination::Exit));
+        end.add_option(option(&[], BdlDestination::Exit));
+        doc.add_node(end).unwrap();
+
+        let imported = BdlDocument::from_twee(&doc.to_twee()).unwrap();
+        assert_eq!(imported.nodes.len(), 2);
+        assert_eq!(imported.nodes["end"].options.len(), 2);
+        assert!(imported.nodes["end"].options[1].keywords.is_empty());
+        assert!(matches!(imported.nodes["end"].options[1].destination, BdlDestination::Exit));
+        assert_eq!(imported.nodes["start"].options.len(), 2);
+        assert!(matches!(
+            &imported.nodes["start"].options[1].destination,
+            BdlDestination::FileTransfer { file, node } if file == "main.bdl" && node == "intro"
+        ));
+        assert!(matches!(imported.nodes["end"].options[0].destination, BdlDestination::Exit));
+
+        // An empty link label is keywordless too, never an empty keyword
+        let doc = BdlDocument::from_twee(":: start\n[[->EXIT]]\n").unwrap();
+        assert!(doc.nodes["start"].options[0].keywords.is_empty());
+    }
+
+    #[test]
+    fn test_from_twee_unterminated_link() {
+        assert!(matches!(
+            BdlDocument::from_twee(":: Start\nGo [[north->North"),
+            Err(BdlError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_canonical_bdl_ignores_input_order() {
+        let build = |reversed: bool| {
+            let mut doc = BdlDocument::new(None);
+            doc.metadata.topic = Some("Order".to_string());
+
+            let mut vars = vec![
+                ("score".to_string(), BdlValue::Integer(0)),
+                ("name".to_string(), BdlValue::String("".to_string())),
+            ];
+            let mut nodes = vec!["start", "end"];
+            let mut options = vec![
+                option(&["go"], BdlDestination::Node("end".to_string())),
+                option(&["quit"], BdlDestination::Exit),
+            ];
+            if reversed {
+                vars.reverse();
+                nodes.reverse();
+                options.reverse();
+            }
+
+            doc.local_vars.extend(vars);
+            for name in nodes {
+                let mut node = BdlNode::new(name.to_string());
+                node.add_content(BdlContentElement::Text(format!("This is {}.  ", name)));
+                if name == "start" {
+                    for option in options.clone() {
+                        node.add_option(option);
+                    }
+                }
+                doc.add_node(node).unwrap();
+            }
+            doc
+        };
+
+        let canonical = build(false).to_canonical_bdl();
+        assert_eq!(canonical, build(true).to_canonical_bdl());
+        assert_eq!(
+            canonical,
+            "# Topic: Order\n\n\
+             $local_vars: {\n    name: \"\",\n    score: 0\n}\n\n\
+             @end\nThis is end.\n\n\
+             @start\nThis is start.\n{go} -> @end\n{quit} -> exit\n\n"
+        );
+    }
+
+    #[test]
+    fn test_canonical_bdl_orders_options_with_equal_keywords() {
+        let build = |reversed: bool| {
+            let mut options = vec![
+                when("lit", option(&["go"], node("hall"))),
+                option(&["go"], node("cellar")),
+                when("a", option(&[], node("x"))),
+                when("b", option(&[], node("y"))),
+            ];
+            if reversed {
+                options.reverse();
+            }
+
+            let mut doc = BdlDocument::new(None);
+            let mut start = BdlNode::new("start".to_string());
+            for option in options {
+                start.add_option(option);
+            }
+            doc.add_node(start).unwrap();
+            doc
+        };
+
+        let canonical = build(false).to_canonical_bdl();
+        assert_eq!(canonical, build(true).to_canonical_bdl());
+        assert_eq!(
+            canonical,
+            "@start\n?{a} -> @x\n?{b} -> @y\n{go} -> @cellar\n?{lit} {go} -> @hall\n\n"
+        );
+    }
+
+    #[test]
+    fn test_to_script() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut greet = BdlNode::new("greet".to_string());
+        greet.add_content(BdlContentElement::Text("Welcome back, ".to_string()));
+        greet.add_content(BdlContentElement::Variable("player".to_string()));
+        greet.add_content(BdlContentElement::Text("!".to_string()));
+        greet.add_content(BdlContentElement::FunctionCall {
+            name: "playChime".to_string(),
+            args: Vec::new(),
+            result_vars: vec![],
+        });
+        greet.add_option(option(&["leave"], BdlDestination::Exit));
+        doc.add_node(greet).unwrap();
+        doc.add_node(BdlNode::new("silent".to_string())).unwrap();
+
+        assert_eq!(doc.to_script(), "## greet\nWelcome back, <player>!\n");
+    }
+
+    #[test]
+    fn test_bdl_string_round_trip() {
+        let content = r#"# Topic: Round trip
+# Author: Tester
+# Required: module1.bdl
+
+$global_vars: {
+    score: 0,
+    name: "the \"guest\""
+}
+
+@start
+Hello ${name}! !{roll -> result, next} Your roll: ${result}
+Score so far: ${score:04.1} ${score == 1 ? "point" : "points, \"so\" far"}
+!{add(score, ${result}, 1.5, "a, \"b\"", true) -> total}
+{go, next} -> @finish  /// the usual way out
+{+roll +again}@1 -> @start
+?{lucky} ~{score >= 10} {gamble} -> [module1.bdl:casino]
+?{tired} -> @finish
+
+@finish #farewell
+Bye, that costs \${fee} from C:\\${dir}.
+See you soon.
+{exit} /// ends the demo
+"#;
+        let parser = crate::parser::BdlParser::new(content.to_string());
+        let doc = parser.parse().unwrap();
+
+        let text = doc.to_bdl_string();
+        let reparsed = crate::parser::BdlParser::new(text.clone()).parse().unwrap();
+        assert_eq!(reparsed.content_hash(), doc.content_hash(), "round trip changed the document:\n{}", text);
+
+        assert!(text.contains("{go, next} -> @finish  /// the usual way out\n{+roll +again}@1 -> @start\n?{lucky} ~{score >= 10} {gamble} -> [module1.bdl:casino]\n?{tired} -> @finish\n"));
+        assert!(text.contains("{exit}  /// ends the demo\n"));
+        assert!(text.contains("!{roll -> result, next}"));
+        assert!(text.contains(r#"Score so far: ${score:04.1} ${score == 1 ? "point" : "points, \"so\" far"}"#));
+        assert!(text.contains(r#"!{add(${score}, ${result}, 1.5, "a, \"b\"", true) -> total}"#));
+    }
+
+    #[test]
+    fn test_split_by_node() {
+        let content = r#"# Topic: Split
+$global_vars: {
+    gold: 5
+}
+
+@start
+Hello, you have ${gold} gold.
+{shop} -> @shop
+
+@shop #merchant
+Buy something?
+?{gold > 0} {buy} -> @end  /// spends the gold
+{leave} -> @start
+
+@end
+{exit}
+"#;
+        let doc = crate::parser::BdlParser::new(content.to_string()).parse().unwrap();
+        let fragments = doc.split_by_node();
+
+        let mut keys: Vec<&str> = fragments.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, vec![SPLIT_MANIFEST_KEY, "end", "shop", "start"]);
+
+        let manifest = crate::parser::BdlParser::new(fragments[SPLIT_MANIFEST_KEY].clone()).parse().unwrap();
+        assert_eq!(manifest.metadata.topic.as_deref(), Some("Split"));
+        assert_eq!(manifest.global_vars, doc.global_vars);
+        assert!(manifest.nodes.is_empty());
+
+        for (name, node) in &doc.nodes {
+            let fragment = crate::parser::BdlParser::new(fragments[name].clone()).parse().unwrap();
+            assert_eq!(fragment.nodes.len(), 1, "fragment for {}:\n{}", name, fragments[name]);
+            assert_eq!(
+                serde_json::to_value(&fragment.nodes[name]).unwrap(),
+                serde_json::to_value(node).unwrap(),
+                "fragment for {}:\n{}",
+                name,
+                fragments[name]
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(option(&["buy", "shop"], node("shop")));
+        start.add_option(when("vip", option(&["bank"], BdlDestination::FileTransfer {
+            file: "bank.bdl".to_string(),
+            node: "vault".to_string(),
+        })));
+        doc.add_node(start).unwrap();
+
+        let mut shop = BdlNode::new("shop".to_string());
+        shop.add_option(option(&["say \"bye\""], BdlDestination::Exit));
+        doc.add_node(shop).unwrap();
+
+        let dot = doc.to_dot();
+        let lines: Vec<&str> = dot.lines().collect();
+        assert_eq!(lines.first(), Some(&"digraph bdl {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        for expected in [
+            r#"    "shop";"#,
+            r#"    "start";"#,
+            r#"    "start" -> "shop" [label="buy, shop"];"#,
+            r#"    "start" -> "bank.bdl:vault" [label="bank", style=dashed];"#,
+            r#"    "shop" -> "[EXIT]" [label="say \"bye\""];"#,
+            r#"    "bank.bdl:vault" [shape=box, style=dashed];"#,
+            r#"    "[EXIT]" [shape=doublecircle];"#,
+        ] {
+            assert!(lines.contains(&expected), "missing {:?} in:\n{}", expected, dot);
+        }
+    }
+
+    #[test]
+    fn test_bdl_string_round_trips_paragraph_breaks() {
+        let content = "@start\nThe hall is quiet.\nDust everywhere.\n\nA door stands open.\n\n{enter} -> @start\n";
+        let options = crate::parser::ParserOptions {
+            preserve_paragraph_breaks: true,
+            ..Default::default()
+        };
+        let doc = crate::parser::BdlParser::with_options(content.to_string(), options.clone()).parse().unwrap();
+        match &doc.nodes["start"].content[..] {
+            [BdlContentElement::Text(text)] => assert_eq!(text, "The hall is quiet.\nDust everywhere.\n\nA door stands open."),
+            other => panic!("Expected one Text element, got {:?}", other),
+        }
+
+        let text = doc.to_bdl_string();
+        assert_eq!(text, "@start\nThe hall is quiet.\nDust everywhere.\n\nA door stands open.\n{enter} -> @start\n\n");
+        let reparsed = crate::parser::BdlParser::with_options(text, options).parse().unwrap();
+        assert_eq!(reparsed.content_hash(), doc.content_hash());
+
+        // Without the option the break is dropped, as before
+        let plain = crate::parser::BdlParser::new(content.to_string()).parse().unwrap();
+        assert!(!plain.to_bdl_string().contains("\n\nA door"));
+    }
+
+    #[test]
+    fn test_bdl_string_round_trips_anonymous_nodes() {
+        let content = "@start\nHi.\n{go} -> @__auto_0\n---\nIt was a dark night.\n---\nStill dark.\n---\nA door creaked.\n{run} -> @start\n";
+        let options = crate::parser::ParserOptions {
+            auto_name_anonymous: true,
+            ..Default::default()
+        };
+        let doc = crate::parser::BdlParser::with_options(content.to_string(), options.clone()).parse().unwrap();
+
+        let text = doc.to_bdl_string();
+        assert!(
+            text.ends_with("---\nIt was a dark night.\n\n---\nStill dark.\n\n---\nA door creaked.\n{run} -> @start\n\n"),
+            "unexpected output:\n{}",
+            text
+        );
+        let reparsed = crate::parser::BdlParser::with_options(text.clone(), options).parse().unwrap();
+        assert_eq!(reparsed.content_hash(), doc.content_hash(), "round trip changed the document:\n{}", text);
+    }
+}
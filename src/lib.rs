@@ -1,22 +1,122 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Deref;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-mod parser;
+pub mod parser;
+mod analysis;
+mod export;
+mod query;
+mod repl;
+mod runtime;
+
+pub use analysis::{ArgType, BranchingStats, FunctionSchema, FunctionSignature, KeywordPolicy};
+pub use export::{MachineAction, MachineState, MachineTransition, StateMachine, SPLIT_MANIFEST_KEY};
+pub use query::QueryMatch;
+pub use repl::BdlRepl;
+pub use runtime::{BdlRuntime, FunctionRegistry, HostFunction, NodeHook};
 
 #[derive(Debug, Error)]
 pub enum BdlError {
-    #[error("Parse error: {0}")]
-    ParseError(String),
-    #[error("Variable error: {0}")]
-    VariableError(String),
-    #[error("Node error: {0}")]
-    NodeError(String),
-    #[error("Dependency error: {0}")]
-    DependencyError(String),
+    #[error("Parse error{}: {0}", .0.location())]
+    ParseError(ErrorMessage),
+    #[error("Variable error{}: {0}", .0.location())]
+    VariableError(ErrorMessage),
+    #[error("Node error{}: {0}", .0.location())]
+    NodeError(ErrorMessage),
+    #[error("Dependency error{}: {0}", .0.location())]
+    DependencyError(ErrorMessage),
+}
+
+impl BdlError {
+    /// Where in the source the error was found, if known
+    pub fn span(&self) -> Option<Span> {
+        self.message().span
+    }
+
+    /// Attach a source position, keeping any position already recorded
+    pub(crate) fn at(mut self, span: Span) -> Self {
+        let message = match &mut self {
+            BdlError::ParseError(m)
+            | BdlError::VariableError(m)
+            | BdlError::NodeError(m)
+            | BdlError::DependencyError(m) => m,
+        };
+        message.span.get_or_insert(span);
+        self
+    }
+
+    fn message(&self) -> &ErrorMessage {
+        match self {
+            BdlError::ParseError(m)
+            | BdlError::VariableError(m)
+            | BdlError::NodeError(m)
+            | BdlError::DependencyError(m) => m,
+        }
+    }
+}
+
+/// A 1-based position in a BDL source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The text of a `BdlError`, with the position it refers to when known
+///
+/// Dereferences to the message text, so it can be matched like a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorMessage {
+    pub text: String,
+    pub span: Option<Span>,
+}
+
+impl ErrorMessage {
+    /// ` at line N` when a position is known, otherwise empty
+    fn location(&self) -> String {
+        match self.span {
+            Some(span) => format!(" at line {}", span.line),
+            None => String::new(),
+        }
+    }
+}
+
+impl From<String> for ErrorMessage {
+    fn from(text: String) -> Self {
+        ErrorMessage { text, span: None }
+    }
+}
+
+impl From<&str> for ErrorMessage {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+impl Deref for ErrorMessage {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.text
+    }
+}
+
+impl fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
 }
 
 /// Represents a complete BDL document
+///
+/// Nodes are stored in a `HashMap`, but no public output depends on its
+/// iteration order: every method that returns a list of nodes, options or
+/// problems sorts it by node name, then by source order within a node, and
+/// text exports write nodes by name. The same document therefore always
+/// produces the same output, which keeps snapshot tests stable.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BdlDocument {
     /// Document metadata
@@ -38,6 +138,10 @@ pub struct BdlMetadata {
     pub author: Option<String>,
     pub version: Option<String>,
     pub required: Option<Vec<String>>,
+    /// Format version declared by a leading `#!bdl <n>` line
+    pub format_version: Option<u32>,
+    /// Entries listed as `# - ...` under a `# Changelog:` header
+    pub changelog: Option<Vec<String>>,
 }
 
 /// Represents a node in the BDL document
@@ -49,6 +153,9 @@ pub struct BdlNode {
     pub content: Vec<BdlContentElement>,
     /// Available options/branches from this node
     pub options: Vec<BdlBranchOption>,
+    /// Labels from `#tag` annotations on the node header, in source order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
 /// Represents different types of content within a node
@@ -58,11 +165,61 @@ pub enum BdlContentElement {
     Text(String),
     /// Variable interpolation: ${var_name}
     Variable(String),
-    /// Function call: !{function_name}
+    /// Variable interpolation with a format spec: ${var_name:02} or ${var_name:.2}
+    FormattedVariable {
+        name: String,
+        spec: FormatSpec,
+    },
+    /// Inline conditional text: ${count == 1 ? "apple" : "apples"}
+    Conditional {
+        condition: BdlCondition,
+        then: String,
+        otherwise: String,
+    },
+    /// Function call: !{function_name} or !{function_name(arg, ...)}
     FunctionCall {
         name: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<BdlArg>,
         result_vars: Vec<String>,
     },
+    /// Host-defined directive: %tag data
+    Custom {
+        tag: String,
+        data: String,
+    },
+    /// Raw data block kept verbatim: %data mime { body }
+    Data {
+        mime: String,
+        body: String,
+    },
+}
+
+/// An argument passed to a function call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BdlArg {
+    /// A literal value
+    Value(BdlValue),
+    /// A variable, resolved when the function is called
+    Variable(String),
+}
+
+/// The `spec` in `${var:spec}`: `[0][width][.precision]`
+///
+/// Applied as in Rust's `format!`: numbers are right-aligned within the
+/// width and other values left-aligned. Zero padding and precision only
+/// apply to numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct FormatSpec {
+    /// Pad with leading zeros instead of spaces (`02`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub zero_pad: bool,
+    /// Minimum width of the rendered value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<usize>,
+    /// Digits after the decimal point (`.2`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<usize>,
 }
 
 /// Represents an option/branch from a node
@@ -72,12 +229,26 @@ pub struct BdlBranchOption {
     pub keywords: Vec<String>,
     /// Destination (node name or file transfer)
     pub destination: BdlDestination,
-    /// Optional condition
+    /// Optional condition; the option is hidden while it fails
     pub condition: Option<BdlCondition>,
+    /// Whether the input must contain every keyword (`{+open +door}`)
+    /// rather than match any one of them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub match_all: bool,
+    /// Documentation from a trailing `/// ...` comment on the option line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub doc: Option<String>,
+    /// Display position from an `@n` annotation, as in `{yes}@1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
+    /// Optional `~{...}` condition; the option stays listed but cannot be
+    /// chosen while it fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<BdlCondition>,
 }
 
 /// Represents a destination for an option
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BdlDestination {
     /// Points to a node in the current file: @node_name
     Node(String),
@@ -88,6 +259,44 @@ pub enum BdlDestination {
     },
     /// Special exit command
     Exit,
+    /// Re-enters the node the option belongs to: @self or @.
+    Current,
+    /// A node named at runtime by a host function: !{function}
+    Computed {
+        function: String,
+    },
+}
+
+/// The variant of a `BdlDestination`, without its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DestinationKind {
+    Node,
+    FileTransfer,
+    Exit,
+    Current,
+    Computed,
+}
+
+impl BdlDestination {
+    /// Which kind of destination this is
+    pub fn kind(&self) -> DestinationKind {
+        match self {
+            BdlDestination::Node(_) => DestinationKind::Node,
+            BdlDestination::FileTransfer { .. } => DestinationKind::FileTransfer,
+            BdlDestination::Exit => DestinationKind::Exit,
+            BdlDestination::Current => DestinationKind::Current,
+            BdlDestination::Computed { .. } => DestinationKind::Computed,
+        }
+    }
+
+    /// The same-file node this destination leads to from the node `from`
+    pub fn local_target<'a>(&'a self, from: &'a str) -> Option<&'a str> {
+        match self {
+            BdlDestination::Node(target) => Some(target),
+            BdlDestination::Current => Some(from),
+            _ => None,
+        }
+    }
 }
 
 /// Represents a condition check
@@ -95,15 +304,62 @@ pub enum BdlDestination {
 pub struct BdlCondition {
     /// Variable name to check
     pub variable: String,
+    /// Comparison against a literal; `None` checks truthiness
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comparison: Option<BdlComparison>,
 }
 
-/// Represents possible values for variables
+/// The `<op> <value>` part of a condition like `?{score > 100}`
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BdlComparison {
+    pub operator: BdlCompareOp,
+    pub value: BdlValue,
+}
+
+/// Comparison operators usable in conditions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BdlCompareOp {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+}
+
+impl BdlCompareOp {
+    /// The operator as written in BDL source
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BdlCompareOp::Eq => "==",
+            BdlCompareOp::Ne => "!=",
+            BdlCompareOp::Gt => ">",
+            BdlCompareOp::Lt => "<",
+            BdlCompareOp::Ge => ">=",
+            BdlCompareOp::Le => "<=",
+        }
+    }
+}
+
+/// Represents possible values for variables
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum BdlValue {
     String(String),
+    /// A whole-number literal such as `42`
+    ///
+    /// Kept exactly over the whole `i64` range, -2^63 to 2^63 - 1, including
+    /// values past 2^53 that an `f64` would round.
+    Integer(i64),
+    /// A literal with a decimal point or exponent such as `42.5`
+    ///
+    /// Whole numbers are only exact up to 2^53 in magnitude.
     Number(f64),
     Boolean(bool),
+    /// A `[a, b, ...]` literal
+    List(Vec<BdlValue>),
+    /// A `{key: value, ...}` literal
+    Map(HashMap<String, BdlValue>),
     Empty,
 }
 
@@ -121,11 +377,327 @@ impl BdlDocument {
     /// Adds a node to the document
     pub fn add_node(&mut self, node: BdlNode) -> Result<(), BdlError> {
         if self.nodes.contains_key(&node.name) {
-            return Err(BdlError::NodeError(format!("Node '{}' already exists", node.name)));
+            return Err(BdlError::NodeError(format!("Node '{}' already exists", node.name).into()));
         }
         self.nodes.insert(node.name.clone(), node);
         Ok(())
     }
+
+    /// Finds all nodes matching a predicate, sorted by node name
+    pub fn find_nodes(&self, pred: impl Fn(&BdlNode) -> bool) -> Vec<&BdlNode> {
+        let mut found: Vec<&BdlNode> = self.nodes.values().filter(|node| pred(node)).collect();
+        found.sort_by(|a, b| a.name.cmp(&b.name));
+        found
+    }
+
+    /// Computes a hash of the document's structure that ignores formatting and map ordering
+    ///
+    /// The document is converted to a JSON value (whose object keys are
+    /// sorted) and hashed with 64-bit FNV-1a, so the result is stable across
+    /// runs and platforms. Option and content order is significant.
+    pub fn content_hash(&self) -> u64 {
+        let canonical = self.canonical_json().to_string();
+
+        canonical.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    /// Serializes the document as indented JSON with object keys in sorted order
+    ///
+    /// Unlike serializing the document directly, the output does not depend on
+    /// `HashMap` iteration order, so it is suitable for committing and diffing.
+    pub fn to_pretty_json(&self) -> String {
+        serde_json::to_string_pretty(&self.canonical_json()).unwrap_or_default()
+    }
+
+    /// The document as a JSON value; `serde_json::Value` objects keep keys sorted
+    fn canonical_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Canonicalizes every option keyword (trimmed, lowercased, inner whitespace collapsed)
+    ///
+    /// Returns `(node, keyword)` pairs, sorted, for each normalized keyword that
+    /// several differently-written keywords in the same node collapsed into.
+    pub fn normalize_keywords(&mut self) -> Vec<(String, String)> {
+        let mut collisions = Vec::new();
+
+        for node in self.nodes.values_mut() {
+            let mut spellings: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+            for option in &mut node.options {
+                for keyword in &mut option.keywords {
+                    let normalized = normalize_keyword(keyword, false);
+                    spellings.entry(normalized.clone()).or_default().insert(keyword.clone());
+                    *keyword = normalized;
+                }
+            }
+
+            for (keyword, originals) in spellings {
+                if originals.len() > 1 {
+                    collisions.push((node.name.clone(), keyword));
+                }
+            }
+        }
+
+        collisions.sort();
+        collisions
+    }
+
+    /// Renames every node to `key_fn(node)` and rewrites local references to match
+    ///
+    /// Fails with a `NodeError`, leaving the document unchanged, if two nodes
+    /// would get the same name. References to nodes that do not exist are
+    /// left as they are.
+    pub fn rekey_nodes(&mut self, key_fn: impl Fn(&BdlNode) -> String) -> Result<(), BdlError> {
+        let mut renames: HashMap<String, String> = HashMap::new();
+        let mut taken: HashMap<String, &str> = HashMap::new();
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+        for name in names {
+            let key = key_fn(&self.nodes[name]);
+            if let Some(other) = taken.insert(key.clone(), name) {
+                return Err(BdlError::NodeError(format!(
+                    "Nodes '{}' and '{}' would both be renamed to '{}'",
+                    other, name, key
+                ).into()));
+            }
+            renames.insert(name.clone(), key);
+        }
+
+        let nodes = std::mem::take(&mut self.nodes);
+        for (_, mut node) in nodes {
+            for option in &mut node.options {
+                if let BdlDestination::Node(target) = &mut option.destination {
+                    if let Some(key) = renames.get(target) {
+                        *target = key.clone();
+                    }
+                }
+            }
+            node.name = renames[&node.name].clone();
+            self.nodes.insert(node.name.clone(), node);
+        }
+
+        Ok(())
+    }
+}
+
+impl BdlCondition {
+    /// Evaluates the condition against a set of variables
+    ///
+    /// Without a comparison, a variable passes if it exists, is not empty,
+    /// and is not `false`, `0`, or the strings `"false"`/`"0"`.
+    ///
+    /// With one, numbers compare numerically and strings lexicographically.
+    /// Booleans, collections and empty values only support `==` and `!=`. Values of
+    /// different types are never equal and never ordered, so only `!=`
+    /// passes. An unset variable fails every comparison.
+    pub fn evaluate(&self, vars: &HashMap<String, BdlValue>) -> bool {
+        let Some(value) = vars.get(&self.variable) else {
+            return false;
+        };
+        let Some(comparison) = &self.comparison else {
+            return value.is_truthy();
+        };
+
+        let ordering = match (value, &comparison.value) {
+            (a, b) if a.as_f64().is_some() && b.as_f64().is_some() => match (a, b) {
+                (BdlValue::Integer(a), BdlValue::Integer(b)) => Some(a.cmp(b)),
+                _ => a.as_f64().partial_cmp(&b.as_f64()),
+            },
+            (BdlValue::String(a), BdlValue::String(b)) => Some(a.cmp(b)),
+            (BdlValue::Boolean(a), BdlValue::Boolean(b)) if a == b => Some(Ordering::Equal),
+            (BdlValue::List(_), BdlValue::List(_)) | (BdlValue::Map(_), BdlValue::Map(_)) if value == &comparison.value => {
+                Some(Ordering::Equal)
+            }
+            (BdlValue::Empty, BdlValue::Empty) => Some(Ordering::Equal),
+            _ => None,
+        };
+
+        match comparison.operator {
+            BdlCompareOp::Eq => ordering == Some(Ordering::Equal),
+            BdlCompareOp::Ne => ordering != Some(Ordering::Equal),
+            // Only numbers and strings are ordered
+            _ if !matches!(value, BdlValue::Integer(_) | BdlValue::Number(_) | BdlValue::String(_)) => false,
+            BdlCompareOp::Gt => ordering == Some(Ordering::Greater),
+            BdlCompareOp::Lt => ordering == Some(Ordering::Less),
+            BdlCompareOp::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            BdlCompareOp::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        }
+    }
+}
+
+impl BdlValue {
+    /// The numeric value of an `Integer` or `Number`
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            BdlValue::Integer(n) => Some(*n as f64),
+            BdlValue::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Whether the value counts as set for a simple `?{var}` condition
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            BdlValue::String(s) => !s.is_empty() && s != "false" && s != "0",
+            BdlValue::Integer(n) => *n != 0,
+            BdlValue::Number(n) => *n != 0.0,
+            BdlValue::Boolean(b) => *b,
+            BdlValue::List(items) => !items.is_empty(),
+            BdlValue::Map(entries) => !entries.is_empty(),
+            BdlValue::Empty => false,
+        }
+    }
+
+    /// Parses a single value token: a quoted string, number, boolean,
+    /// `[...]` list, `{...}` map, or empty (nothing)
+    ///
+    /// Collections may nest; `[]` and `{}` are an empty list and map. A
+    /// whole number outside the `i64` range is an error rather than being
+    /// rounded to a `Number`; write it with a decimal point to get a float.
+    pub fn parse(s: &str) -> Result<BdlValue, BdlError> {
+        let value = s.trim();
+
+        if let Some(inner) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            let items = split_top_level(inner, ',')?;
+            Ok(BdlValue::List(items.into_iter().map(BdlValue::parse).collect::<Result<_, _>>()?))
+        } else if let Some(inner) = value.strip_prefix('{').and_then(|v| v.strip_suffix('}')) {
+            let mut entries = HashMap::new();
+            for entry in split_top_level(inner, ',')? {
+                let (key, item) = split_top_level(entry, ':')?
+                    .into_iter()
+                    .next()
+                    .filter(|key| key.len() < entry.len())
+                    .map(|key| (key.trim(), &entry[key.len() + 1..]))
+                    .ok_or_else(|| BdlError::ParseError(format!("Invalid map entry: {}", entry.trim()).into()))?;
+                // Keys are bare words or quoted strings
+                let key = match BdlValue::parse(key) {
+                    Ok(BdlValue::String(quoted)) if !quoted.is_empty() => quoted,
+                    _ if !key.starts_with('"') => key.to_string(),
+                    _ => return Err(BdlError::ParseError(format!("Invalid map key: {}", key).into())),
+                };
+                if entries.insert(key.clone(), BdlValue::parse(item)?).is_some() {
+                    return Err(BdlError::ParseError(format!("Duplicate map key: {}", key).into()));
+                }
+            }
+            Ok(BdlValue::Map(entries))
+        } else if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            Ok(BdlValue::String(unescape_string(&value[1..value.len() - 1])?))
+        } else if value == "true" {
+            Ok(BdlValue::Boolean(true))
+        } else if value == "false" {
+            Ok(BdlValue::Boolean(false))
+        } else if is_whole_number(value) {
+            value
+                .parse::<i64>()
+                .map(BdlValue::Integer)
+                .map_err(|_| BdlError::ParseError(format!("Integer out of range: {}", value).into()))
+        } else if let Ok(number) = value.parse::<f64>() {
+            Ok(BdlValue::Number(number))
+        } else if value.is_empty() {
+            Ok(BdlValue::Empty)
+        } else {
+            Err(BdlError::ParseError(format!("Invalid value format: {}", value).into()))
+        }
+    }
+}
+
+/// Whether `s` is an optionally signed run of ASCII digits
+fn is_whole_number(s: &str) -> bool {
+    let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Splits `s` on `separator` where it is outside quotes and brackets
+///
+/// An all-blank `s` yields no parts; otherwise every part must be non-blank.
+fn split_top_level(s: &str, separator: char) -> Result<Vec<&str>, BdlError> {
+    if s.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| BdlError::ParseError(format!("Unbalanced brackets in: {}", s).into()))?;
+            }
+            _ if c == separator && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 || in_string {
+        return Err(BdlError::ParseError(format!("Unbalanced brackets in: {}", s).into()));
+    }
+    parts.push(&s[start..]);
+
+    if parts.iter().any(|part| part.trim().is_empty()) {
+        return Err(BdlError::ParseError(format!("Empty element in: {}", s).into()));
+    }
+    Ok(parts)
+}
+
+/// Decodes `\"`, `\\`, `\n` and `\t` in the body of a quoted string
+fn unescape_string(body: &str) -> Result<String, BdlError> {
+    let mut decoded = String::with_capacity(body.len());
+    let mut chars = body.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('"') => decoded.push('"'),
+                Some('\\') => decoded.push('\\'),
+                Some('n') => decoded.push('\n'),
+                Some('t') => decoded.push('\t'),
+                Some(other) => {
+                    return Err(BdlError::ParseError(format!("Invalid escape '\\{}' in \"{}\"", other, body).into()))
+                }
+                None => return Err(BdlError::ParseError(format!("Unterminated string: \"{}", body).into())),
+            },
+            '"' => return Err(BdlError::ParseError(format!("Unescaped quote in \"{}\"", body).into())),
+            c => decoded.push(c),
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// Trims and collapses internal whitespace in a keyword, lowercasing it
+/// unless `case_sensitive`
+///
+/// This is the form in which `BdlRuntime::choose` compares input with
+/// keywords.
+pub(crate) fn normalize_keyword(keyword: &str, case_sensitive: bool) -> String {
+    let keyword = keyword.split_whitespace().collect::<Vec<_>>().join(" ");
+    if case_sensitive {
+        keyword
+    } else {
+        keyword.to_lowercase()
+    }
 }
 
 impl BdlNode {
@@ -135,6 +707,7 @@ impl BdlNode {
             name,
             content: Vec::new(),
             options: Vec::new(),
+            tags: Vec::new(),
         }
     }
 
@@ -147,6 +720,56 @@ impl BdlNode {
     pub fn add_option(&mut self, option: BdlBranchOption) {
         self.options.push(option);
     }
+
+    /// The options sorted by their `@n` display order annotation
+    ///
+    /// Options with equal order keep their source order, and options without
+    /// an annotation follow all annotated ones.
+    pub fn options_in_display_order(&self) -> Vec<&BdlBranchOption> {
+        let mut options: Vec<&BdlBranchOption> = self.options.iter().collect();
+        options.sort_by_key(|option| (option.order.is_none(), option.order));
+        options
+    }
+
+    /// Whether the node carries the `#allow-unreachable` tag
+    pub fn allows_unreachable(&self) -> bool {
+        self.tags.iter().any(|tag| tag == parser::ALLOW_UNREACHABLE_TAG)
+    }
+}
+
+/// Builders for the documents used by the unit tests of every module
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use crate::{BdlBranchOption, BdlCondition, BdlDestination};
+
+    /// An unconditional option triggered by any of `keywords`
+    pub(crate) fn option(keywords: &[&str], destination: BdlDestination) -> BdlBranchOption {
+        BdlBranchOption {
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+            destination,
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        }
+    }
+
+    /// A destination naming a node in the same file
+    pub(crate) fn node(name: &str) -> BdlDestination {
+        BdlDestination::Node(name.to_string())
+    }
+
+    /// `option`, only available while `variable` is truthy
+    pub(crate) fn when(variable: &str, option: BdlBranchOption) -> BdlBranchOption {
+        BdlBranchOption {
+            condition: Some(BdlCondition {
+                variable: variable.to_string(),
+                comparison: None,
+            }),
+            ..option
+        }
+    }
 }
 
 #[cfg(test)]
@@ -173,6 +796,7 @@ mod tests {
             author: Some("Test Author".to_string()),
             version: Some("1.0".to_string()),
             required: Some(vec!["dep1.bdl".to_string()]),
+            ..Default::default()
         };
         let doc = BdlDocument::new(Some(metadata.clone()));
         assert_eq!(doc.metadata.topic, Some("Test Topic".to_string()));
@@ -194,6 +818,10 @@ mod tests {
             keywords: vec!["next".to_string()],
             destination: BdlDestination::Node("next_node".to_string()),
             condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
         });
 
         assert!(doc.add_node(node.clone()).is_ok());
@@ -214,6 +842,7 @@ mod tests {
         node.add_content(BdlContentElement::Variable("name".to_string()));
         node.add_content(BdlContentElement::FunctionCall {
             name: "getTime".to_string(),
+            args: Vec::new(),
             result_vars: vec!["time".to_string()],
         });
 
@@ -234,6 +863,10 @@ mod tests {
             keywords: vec!["next".to_string()],
             destination: BdlDestination::Node("next_node".to_string()),
             condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
         });
 
         // Test file transfer destination
@@ -244,6 +877,10 @@ mod tests {
                 node: "start".to_string(),
             },
             condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
         });
 
         // Test exit destination
@@ -252,7 +889,12 @@ mod tests {
             destination: BdlDestination::Exit,
             condition: Some(BdlCondition {
                 variable: "can_exit".to_string(),
+                comparison: None,
             }),
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
         });
 
         assert_eq!(node.options.len(), 3);
@@ -278,4 +920,356 @@ mod tests {
         assert!(matches!(vars.get("boolean"), Some(BdlValue::Boolean(_))));
         assert!(matches!(vars.get("empty"), Some(BdlValue::Empty)));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_find_nodes_with_exit_option() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut menu = BdlNode::new("menu".to_string());
+        menu.add_option(BdlBranchOption {
+            keywords: vec!["play".to_string()],
+            destination: BdlDestination::Node("game".to_string()),
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(menu).unwrap();
+
+        for name in ["goodbye", "game"] {
+            let mut node = BdlNode::new(name.to_string());
+            node.add_option(BdlBranchOption {
+                keywords: vec!["quit".to_string()],
+                destination: BdlDestination::Exit,
+                condition: None,
+                match_all: false,
+                doc: None,
+                order: None,
+                enabled: None,
+            });
+            doc.add_node(node).unwrap();
+        }
+
+        let found = doc.find_nodes(|node| {
+            node.options.iter().any(|o| matches!(o.destination, BdlDestination::Exit))
+        });
+        let names: Vec<&str> = found.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["game", "goodbye"]);
+    }
+
+    #[test]
+    fn test_normalize_keywords_reports_collisions() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut node = BdlNode::new("ask".to_string());
+        node.add_option(BdlBranchOption {
+            keywords: vec!["Yes".to_string(), "Of   Course".to_string()],
+            destination: BdlDestination::Node("accept".to_string()),
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        node.add_option(BdlBranchOption {
+            keywords: vec!["yes ".to_string()],
+            destination: BdlDestination::Node("other".to_string()),
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(node).unwrap();
+
+        let collisions = doc.normalize_keywords();
+        assert_eq!(collisions, vec![("ask".to_string(), "yes".to_string())]);
+
+        let node = &doc.nodes["ask"];
+        assert_eq!(node.options[0].keywords, vec!["yes", "of course"]);
+        assert_eq!(node.options[1].keywords, vec!["yes"]);
+
+        // Already-normalized keywords don't collide again
+        assert!(doc.normalize_keywords().is_empty());
+    }
+
+    #[test]
+    fn test_value_parse() {
+        assert!(matches!(BdlValue::parse("\"hello\""), Ok(BdlValue::String(s)) if s == "hello"));
+        assert!(matches!(BdlValue::parse("\"\""), Ok(BdlValue::String(s)) if s.is_empty()));
+        assert!(matches!(BdlValue::parse(" 42.5 "), Ok(BdlValue::Number(n)) if n == 42.5));
+        assert!(matches!(BdlValue::parse("true"), Ok(BdlValue::Boolean(true))));
+        assert!(matches!(BdlValue::parse("false"), Ok(BdlValue::Boolean(false))));
+        assert!(matches!(BdlValue::parse("{}"), Ok(BdlValue::Map(m)) if m.is_empty()));
+        assert!(matches!(BdlValue::parse("[]"), Ok(BdlValue::List(l)) if l.is_empty()));
+        assert!(matches!(BdlValue::parse(""), Ok(BdlValue::Empty)));
+        assert!(matches!(BdlValue::parse("not_valid"), Err(BdlError::ParseError(_))));
+    }
+
+    #[test]
+    fn test_condition_truthiness() {
+        let condition = BdlCondition {
+            variable: "flag".to_string(),
+            comparison: None,
+        };
+        let mut vars = HashMap::new();
+        assert!(!condition.evaluate(&vars));
+
+        for falsy in [
+            BdlValue::Empty,
+            BdlValue::Boolean(false),
+            BdlValue::Number(0.0),
+            BdlValue::String(String::new()),
+            BdlValue::String("false".to_string()),
+            BdlValue::String("0".to_string()),
+        ] {
+            vars.insert("flag".to_string(), falsy);
+            assert!(!condition.evaluate(&vars));
+        }
+
+        vars.insert("flag".to_string(), BdlValue::String("yes".to_string()));
+        assert!(condition.evaluate(&vars));
+        vars.insert("flag".to_string(), BdlValue::Number(3.0));
+        assert!(condition.evaluate(&vars));
+    }
+
+    #[test]
+    fn test_content_hash_ignores_ordering() {
+        use crate::parser::BdlParser;
+
+        let parse = |content: &str| {
+            let parser = BdlParser::new(content.to_string());
+            let mut doc = BdlDocument::new(Some(parser.parse_metadata().unwrap()));
+            let (global, local) = parser.parse_variables().unwrap();
+            doc.global_vars = global;
+            doc.local_vars = local;
+            doc.nodes = parser.parse_nodes(&std::collections::HashSet::new()).unwrap();
+            doc
+        };
+
+        let first = parse("# Topic: Hash\n$local_vars: {\n    a: 1,\n    b: 2\n}\n@one\nFirst\n@two\nSecond");
+        let reordered = parse("# Topic:   Hash\n\n$local_vars: {\n  b: 2,\n  a: 1\n}\n\n@two\n   Second\n\n@one\nFirst\n");
+        assert_eq!(first.content_hash(), reordered.content_hash());
+
+        let changed = parse("# Topic: Hash\n$local_vars: {\n    a: 1,\n    b: 3\n}\n@one\nFirst\n@two\nSecond");
+        assert_ne!(first.content_hash(), changed.content_hash());
+    }
+
+    #[test]
+    fn test_pretty_json_sorted_keys() {
+        let mut doc = BdlDocument::new(None);
+        for name in ["zeta", "alpha", "mid", "beta"] {
+            doc.add_node(BdlNode::new(name.to_string())).unwrap();
+        }
+        doc.local_vars.insert("z_var".to_string(), BdlValue::Number(1.0));
+        doc.local_vars.insert("a_var".to_string(), BdlValue::Boolean(true));
+
+        let json = doc.to_pretty_json();
+        let position = |needle: &str| json.find(needle).unwrap();
+        assert!(position("\"alpha\"") < position("\"beta\""));
+        assert!(position("\"beta\"") < position("\"mid\""));
+        assert!(position("\"mid\"") < position("\"zeta\""));
+        assert!(position("\"a_var\"") < position("\"z_var\""));
+        assert!(json.contains("\n  \"local_vars\": {"));
+
+        // A clone with independently built maps serializes identically
+        let mut rebuilt = BdlDocument::new(None);
+        for name in ["beta", "mid", "alpha", "zeta"] {
+            rebuilt.add_node(BdlNode::new(name.to_string())).unwrap();
+        }
+        rebuilt.local_vars.insert("a_var".to_string(), BdlValue::Boolean(true));
+        rebuilt.local_vars.insert("z_var".to_string(), BdlValue::Number(1.0));
+        assert_eq!(json, rebuilt.to_pretty_json());
+    }
+
+    #[test]
+    fn test_rekey_nodes_updates_references() {
+        let mut doc = BdlDocument::new(None);
+
+        let mut start = BdlNode::new("start".to_string());
+        start.add_option(BdlBranchOption {
+            keywords: vec!["go".to_string()],
+            destination: BdlDestination::Node("end".to_string()),
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        start.add_option(BdlBranchOption {
+            keywords: vec!["away".to_string()],
+            destination: BdlDestination::FileTransfer {
+                file: "other.bdl".to_string(),
+                node: "end".to_string(),
+            },
+            condition: None,
+            match_all: false,
+            doc: None,
+            order: None,
+            enabled: None,
+        });
+        doc.add_node(start).unwrap();
+        doc.add_node(BdlNode::new("end".to_string())).unwrap();
+
+        doc.rekey_nodes(|node| format!("intro_{}", node.name)).unwrap();
+
+        let mut names: Vec<&String> = doc.nodes.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["intro_end", "intro_start"]);
+
+        let start = &doc.nodes["intro_start"];
+        assert_eq!(start.name, "intro_start");
+        assert_eq!(start.options[0].destination, BdlDestination::Node("intro_end".to_string()));
+        assert_eq!(
+            start.options[1].destination,
+            BdlDestination::FileTransfer { file: "other.bdl".to_string(), node: "end".to_string() }
+        );
+
+        // Collisions are rejected without touching the document
+        assert!(matches!(doc.rekey_nodes(|_| "same".to_string()), Err(BdlError::NodeError(_))));
+        assert!(doc.nodes.contains_key("intro_start"));
+    }
+
+    #[test]
+    fn test_value_parse_escapes() {
+        assert!(matches!(
+            BdlValue::parse(r#""she said \"hi\"""#),
+            Ok(BdlValue::String(s)) if s == "she said \"hi\""
+        ));
+        assert!(matches!(
+            BdlValue::parse(r#""a\\b\tc\nd""#),
+            Ok(BdlValue::String(s)) if s == "a\\b\tc\nd"
+        ));
+        for invalid in [r#""bad \q""#, r#""open\""#, r#""two" "parts""#] {
+            assert!(matches!(BdlValue::parse(invalid), Err(BdlError::ParseError(_))), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_condition_comparisons() {
+        let compare = |operator, value| BdlCondition {
+            variable: "x".to_string(),
+            comparison: Some(BdlComparison { operator, value }),
+        };
+        let vars = |value| HashMap::from([("x".to_string(), value)]);
+
+        let score = vars(BdlValue::Number(100.0));
+        for (op, expected) in [
+            (BdlCompareOp::Eq, true),
+            (BdlCompareOp::Ne, false),
+            (BdlCompareOp::Gt, false),
+            (BdlCompareOp::Lt, false),
+            (BdlCompareOp::Ge, true),
+            (BdlCompareOp::Le, true),
+        ] {
+            assert_eq!(compare(op, BdlValue::Number(100.0)).evaluate(&score), expected, "{:?}", op);
+        }
+        assert!(compare(BdlCompareOp::Gt, BdlValue::Number(99.5)).evaluate(&score));
+        assert!(compare(BdlCompareOp::Lt, BdlValue::Number(100.5)).evaluate(&score));
+
+        let name = vars(BdlValue::String("Bob".to_string()));
+        assert!(compare(BdlCompareOp::Eq, BdlValue::String("Bob".to_string())).evaluate(&name));
+        assert!(compare(BdlCompareOp::Ne, BdlValue::String("Ann".to_string())).evaluate(&name));
+        assert!(compare(BdlCompareOp::Gt, BdlValue::String("Ann".to_string())).evaluate(&name));
+
+        let flag = vars(BdlValue::Boolean(true));
+        assert!(compare(BdlCompareOp::Eq, BdlValue::Boolean(true)).evaluate(&flag));
+        assert!(!compare(BdlCompareOp::Gt, BdlValue::Boolean(false)).evaluate(&flag));
+
+        // Mismatched types are unequal and unordered
+        let text_number = vars(BdlValue::String("100".to_string()));
+        assert!(!compare(BdlCompareOp::Eq, BdlValue::Number(100.0)).evaluate(&text_number));
+        assert!(compare(BdlCompareOp::Ne, BdlValue::Number(100.0)).evaluate(&text_number));
+        assert!(!compare(BdlCompareOp::Ge, BdlValue::Number(1.0)).evaluate(&text_number));
+        assert!(!compare(BdlCompareOp::Lt, BdlValue::Number(1000.0)).evaluate(&text_number));
+
+        // Unset variables fail even `!=`
+        assert!(!compare(BdlCompareOp::Ne, BdlValue::Number(1.0)).evaluate(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_integer_values() {
+        assert!(matches!(BdlValue::parse("42"), Ok(BdlValue::Integer(42))));
+        assert!(matches!(BdlValue::parse("-7"), Ok(BdlValue::Integer(-7))));
+        assert!(matches!(BdlValue::parse("42.5"), Ok(BdlValue::Number(n)) if n == 42.5));
+        assert!(matches!(BdlValue::parse("42.0"), Ok(BdlValue::Number(n)) if n == 42.0));
+        assert!(matches!(BdlValue::parse("1e3"), Ok(BdlValue::Number(n)) if n == 1000.0));
+
+        assert!(!BdlValue::Integer(0).is_truthy());
+        assert!(BdlValue::Integer(5).is_truthy());
+
+        // Integers and floats compare by numeric value
+        let vars = HashMap::from([("x".to_string(), BdlValue::Integer(5))]);
+        let compare = |operator, value| BdlCondition {
+            variable: "x".to_string(),
+            comparison: Some(BdlComparison { operator, value }),
+        };
+        assert!(compare(BdlCompareOp::Eq, BdlValue::Number(5.0)).evaluate(&vars));
+        assert!(compare(BdlCompareOp::Lt, BdlValue::Number(5.5)).evaluate(&vars));
+        assert!(compare(BdlCompareOp::Ge, BdlValue::Integer(5)).evaluate(&vars));
+
+        // JSON keeps the distinction
+        let json = serde_json::to_string(&vec![BdlValue::Integer(3), BdlValue::Number(3.0)]).unwrap();
+        assert_eq!(json, "[3,3.0]");
+        let values: Vec<BdlValue> = serde_json::from_str(&json).unwrap();
+        assert!(matches!(values[..], [BdlValue::Integer(3), BdlValue::Number(_)]));
+    }
+
+    #[test]
+    fn test_integer_range() {
+        // 2^53 is the last integer f64 holds exactly; the next one survives as an i64
+        assert_eq!(BdlValue::parse("9007199254740992").unwrap(), BdlValue::Integer(1 << 53));
+        assert_eq!(BdlValue::parse("9007199254740993").unwrap(), BdlValue::Integer((1 << 53) + 1));
+        assert_eq!(BdlValue::parse("-9007199254740993").unwrap(), BdlValue::Integer(-(1 << 53) - 1));
+
+        assert_eq!(BdlValue::parse("9223372036854775807").unwrap(), BdlValue::Integer(i64::MAX));
+        assert_eq!(BdlValue::parse("-9223372036854775808").unwrap(), BdlValue::Integer(i64::MIN));
+        for literal in ["9223372036854775808", "-9223372036854775809", "+99999999999999999999"] {
+            assert!(
+                matches!(BdlValue::parse(literal), Err(BdlError::ParseError(msg)) if msg.contains("Integer out of range")),
+                "{} should be out of range",
+                literal
+            );
+        }
+
+        // A decimal point still asks for a float
+        assert!(matches!(BdlValue::parse("9223372036854775808.0"), Ok(BdlValue::Number(n)) if n == 9223372036854775808.0));
+
+        let doc = crate::parser::BdlParser::new("$global_vars: {\n    id: 9007199254740993\n}\n".to_string())
+            .parse()
+            .unwrap();
+        assert_eq!(doc.global_vars.unwrap()["id"], BdlValue::Integer(9007199254740993));
+    }
+
+    #[test]
+    fn test_nested_collection_values() {
+        let value = BdlValue::parse(r#"[{name: "sword, sharp", dmg: 3}, {name: "shield", tags: ["wood", []]}]"#).unwrap();
+
+        let BdlValue::List(items) = &value else {
+            panic!("Expected a list, got {:?}", value);
+        };
+        assert_eq!(items.len(), 2);
+        let BdlValue::Map(sword) = &items[0] else {
+            panic!("Expected a map, got {:?}", items[0]);
+        };
+        assert_eq!(sword["name"], BdlValue::String("sword, sharp".to_string()));
+        assert_eq!(sword["dmg"], BdlValue::Integer(3));
+        let BdlValue::Map(shield) = &items[1] else {
+            panic!("Expected a map, got {:?}", items[1]);
+        };
+        assert_eq!(
+            shield["tags"],
+            BdlValue::List(vec![BdlValue::String("wood".to_string()), BdlValue::List(Vec::new())])
+        );
+
+        // Collections survive a JSON round trip through the untagged representation
+        let json = serde_json::to_string(&value).unwrap();
+        let back: BdlValue = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, value);
+
+        for invalid in ["[1, 2", "[1,, 2]", "{a 1}", "{a: 1, a: 2}", "{: 1}", "[1, 2]]"] {
+            assert!(matches!(BdlValue::parse(invalid), Err(BdlError::ParseError(_))), "{}", invalid);
+        }
+    }
+}
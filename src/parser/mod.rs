@@ -1,14 +1,198 @@
-use crate::{BdlMetadata, BdlError, BdlValue, BdlDocument, BdlDestination, BdlNode, BdlContentElement, BdlBranchOption, BdlCondition};
+use crate::{BdlArg, BdlMetadata, BdlError, BdlValue, BdlDestination, BdlNode, BdlContentElement, BdlBranchOption, BdlCondition, BdlComparison, BdlCompareOp, BdlDocument, FormatSpec, Span};
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
 
+/// BDL format versions understood by this parser (declared as `#!bdl <n>`)
+pub const SUPPORTED_FORMAT_VERSIONS: &[u32] = &[1];
+
+/// Line that starts an unnamed node when `auto_name_anonymous` is enabled
+pub const ANONYMOUS_NODE_MARKER: &str = "---";
+
+/// Prefix of the names given to unnamed nodes (`__auto_0`, `__auto_1`, ...)
+pub const AUTO_NODE_PREFIX: &str = "__auto_";
+
+/// Node tag (`@debug #allow-unreachable`) marking a node as intentionally unreferenced
+pub const ALLOW_UNREACHABLE_TAG: &str = "allow-unreachable";
+
+/// Global and local variable maps produced by `parse_variables`
+pub type ParsedVariables = (Option<HashMap<String, BdlValue>>, HashMap<String, BdlValue>);
+
+/// How options pointing at a node removed by an `#if` gate are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GatedNodePolicy {
+    /// Silently drop the option
+    #[default]
+    Drop,
+    /// Report a `NodeError`
+    Error,
+}
+
+/// How a `%directive` line with no registered handler is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownDirectivePolicy {
+    /// Keep the line as plain text
+    #[default]
+    PassThrough,
+    /// Report a `ParseError`
+    Error,
+}
+
+/// Handler turning the arguments of a `%directive` line into custom element data
+pub type DirectiveHandler = Box<dyn Fn(&str) -> Result<String, BdlError>>;
+
+/// Options controlling how a document is parsed
+#[derive(Debug, Clone, Default)]
+pub struct ParserOptions {
+    /// Flags that enable nodes gated with `@node #if <flag>`
+    pub enabled_flags: HashSet<String>,
+    /// Handling of options that target a gated-out node
+    pub gated_node_policy: GatedNodePolicy,
+    /// Keep leading whitespace on node content lines instead of trimming it
+    pub preserve_indentation: bool,
+    /// Require the `# Version:` metadata value to be valid semver
+    pub strict_version: bool,
+    /// Maximum length in bytes of a single physical line, if limited
+    pub max_line_length: Option<usize>,
+    /// Handling of `%directive` lines without a registered handler
+    pub unknown_directive_policy: UnknownDirectivePolicy,
+    /// Start an auto-named node at each `---` line; consecutive unnamed
+    /// nodes without options fall through to the next one
+    pub auto_name_anonymous: bool,
+    /// Keep blank lines between text lines of a node as extra `\n`s in its
+    /// `Text`, so `to_bdl_string` writes the paragraph breaks back
+    pub preserve_paragraph_breaks: bool,
+}
+
+/// Which variable block is currently being parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VariableScope {
+    Global,
+    Local,
+}
+
+impl VariableScope {
+    fn block_name(self) -> &'static str {
+        match self {
+            VariableScope::Global => "$global_vars",
+            VariableScope::Local => "$local_vars",
+        }
+    }
+}
+
 pub struct BdlParser {
     content: String,
+    options: ParserOptions,
+    filename: Option<String>,
+    directives: HashMap<String, DirectiveHandler>,
 }
 
 impl BdlParser {
     pub fn new(content: String) -> Self {
-        Self { content }
+        Self::with_options(content, ParserOptions::default())
+    }
+
+    /// Creates a parser with custom options
+    pub fn with_options(content: String, options: ParserOptions) -> Self {
+        Self {
+            content,
+            options,
+            filename: None,
+            directives: HashMap::new(),
+        }
+    }
+
+    /// Creates a parser from lines arriving one at a time (without line terminators)
+    ///
+    /// The lines are buffered into the parser's content, so the usual parse
+    /// methods apply unchanged.
+    pub fn from_lines(lines: impl Iterator<Item = String>) -> Self {
+        let mut content = String::new();
+        for line in lines {
+            content.push_str(&line);
+            content.push('\n');
+        }
+        Self::new(content)
+    }
+
+    /// Sets the name of the file being parsed
+    ///
+    /// File-specific rules, such as global variables only being allowed in
+    /// `main.bdl`, are only enforced when a filename is known. Chain it after
+    /// `new` or `with_options`.
+    pub fn with_filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// Registers a handler for `%tag ...` lines in node content
+    ///
+    /// The handler receives the text after the tag and returns the data
+    /// stored in the resulting `BdlContentElement::Custom`.
+    pub fn register_directive(
+        &mut self,
+        tag: impl Into<String>,
+        handler: impl Fn(&str) -> Result<String, BdlError> + 'static,
+    ) {
+        self.directives.insert(tag.into(), Box::new(handler));
+    }
+
+    /// Whether the parsed file is the `main.bdl` entry point
+    fn is_main_file(&self) -> bool {
+        self.filename
+            .as_deref()
+            .and_then(|name| Path::new(name).file_name())
+            .is_some_and(|name| name == "main.bdl")
+    }
+
+    /// Split the content into logical lines, joining `\`-continued lines
+    ///
+    /// A line ending in a single `\` is joined with the next line (whose
+    /// leading indentation is dropped). A line ending in `\\` is not
+    /// continued and keeps one literal backslash.
+    fn logical_lines(&self) -> Result<Vec<String>, BdlError> {
+        Ok(self.numbered_lines()?.into_iter().map(|(_, line)| line).collect())
+    }
+
+    /// Logical lines paired with the 1-based source line each one starts on
+    fn numbered_lines(&self) -> Result<Vec<(usize, String)>, BdlError> {
+        let mut lines = Vec::new();
+        let mut pending: Option<(usize, String)> = None;
+
+        for (index, line) in self.content.lines().enumerate() {
+            // Check before copying so an oversized line is never buffered
+            if let Some(max) = self.options.max_line_length {
+                if line.len() > max {
+                    return Err(BdlError::ParseError(format!(
+                        "Line {} exceeds maximum length of {} bytes",
+                        index + 1, max
+                    ).into()).at(Span { line: index + 1, column: max + 1 }));
+                }
+            }
+
+            let (number, line) = match pending.take() {
+                Some((number, mut joined)) => {
+                    joined.push_str(line.trim_start());
+                    (number, joined)
+                }
+                None => (index + 1, line.to_string()),
+            };
+
+            let trimmed = line.trim_end();
+            if let Some(escaped) = trimmed.strip_suffix("\\\\") {
+                lines.push((number, format!("{}\\", escaped)));
+            } else if let Some(continued) = trimmed.strip_suffix('\\') {
+                pending = Some((number, continued.to_string()));
+            } else {
+                lines.push((number, line));
+            }
+        }
+
+        // A continuation on the last line has nothing to join with
+        if let Some(rest) = pending {
+            lines.push(rest);
+        }
+
+        Ok(lines)
     }
 
     /// Validate a dependency file name
@@ -16,7 +200,7 @@ impl BdlParser {
         // Check file extension
         if !file.ends_with(".bdl") {
             return Err(BdlError::DependencyError(
-                format!("Invalid dependency file extension: {}", file)
+                format!("Invalid dependency file extension: {}", file).into()
             ));
         }
 
@@ -27,20 +211,12 @@ impl BdlParser {
     }
 
     /// Validate a list of dependencies
-    fn validate_dependencies(&self, dependencies: &[String]) -> Result<HashSet<String>, BdlError> {
-        let mut validated = HashSet::new();
-        
+    pub fn validate_dependencies(&self, dependencies: &[String]) -> Result<HashSet<String>, BdlError> {
         for dep in dependencies {
             self.validate_dependency_file(dep)?;
-            
-            if !validated.insert(dep.clone()) {
-                return Err(BdlError::DependencyError(
-                    format!("Duplicate dependency: {}", dep)
-                ));
-            }
         }
 
-        Ok(validated)
+        unique_dependencies(dependencies)
     }
 
     /// Validate that a file transfer destination is allowed by dependencies
@@ -49,26 +225,129 @@ impl BdlParser {
         
         if !dependencies.contains(file) {
             return Err(BdlError::DependencyError(
-                format!("Undeclared dependency: {}", file)
+                format!("Undeclared dependency: {}", file).into()
             ));
         }
 
         Ok(())
     }
 
+    /// Parse the whole file into a `BdlDocument`
+    ///
+    /// Runs the metadata, variable and node stages in order. The metadata's
+    /// `Required` list is checked once, by `validate_dependencies`, which
+    /// also yields the dependency set. The first error from any stage is
+    /// returned.
+    pub fn parse(&self) -> Result<BdlDocument, BdlError> {
+        let metadata = self.read_metadata()?;
+        self.check_version(&metadata)?;
+        let dependencies = self.validate_dependencies(metadata.required.as_deref().unwrap_or_default())?;
+        let (global_vars, local_vars) = self.parse_variables()?;
+        let nodes = self.parse_nodes(&dependencies)?;
+
+        Ok(BdlDocument {
+            metadata,
+            global_vars,
+            local_vars,
+            nodes,
+        })
+    }
+
+    /// Parse the whole file, collecting errors instead of stopping at the first
+    ///
+    /// A bad `Required` list or version is recorded without losing the rest
+    /// of the metadata. Invalid variable lines and duplicate variables are
+    /// recorded with their
+    /// line and skipped, as is every error in the node stage: a node whose
+    /// header is invalid or duplicated is dropped along with its content, and
+    /// a bad option, text line, directive or `%data` block is dropped from its
+    /// node. Any other variable-stage error ends that stage, leaving the
+    /// variables empty. The returned document holds everything that parsed
+    /// cleanly.
+    pub fn parse_all(&self) -> (BdlDocument, Vec<BdlError>) {
+        let mut errors = Vec::new();
+
+        let metadata = self.read_metadata().unwrap_or_else(|e| {
+            errors.push(e);
+            BdlMetadata::default()
+        });
+        if let Err(e) = self.check_version(&metadata) {
+            errors.push(e);
+        }
+
+        // Options may still name the listed files even if the list is flawed
+        let required = metadata.required.as_deref().unwrap_or_default();
+        let dependencies = self.validate_dependencies(required).unwrap_or_else(|e| {
+            errors.push(e);
+            required.iter().cloned().collect()
+        });
+
+        let mut at = None;
+        let (global_vars, local_vars) = self.parse_variables_at(&mut at, &mut errors).unwrap_or_else(|e| {
+            errors.push(locate(e, at));
+            (None, HashMap::new())
+        });
+
+        let mut at = None;
+        let nodes = self.parse_nodes_at(&dependencies, &mut at, &mut errors).unwrap_or_else(|e| {
+            errors.push(locate(e, at));
+            HashMap::new()
+        });
+
+        let document = BdlDocument {
+            metadata,
+            global_vars,
+            local_vars,
+            nodes,
+        };
+        (document, errors)
+    }
+
     /// Parse metadata from the beginning of the file
+    ///
+    /// A `Required` list naming the same file twice is rejected, as is an
+    /// invalid version when `strict_version` is set.
     pub fn parse_metadata(&self) -> Result<BdlMetadata, BdlError> {
+        let metadata = self.read_metadata()?;
+        if let Some(required) = &metadata.required {
+            unique_dependencies(required)?;
+        }
+        self.check_version(&metadata)?;
+
+        Ok(metadata)
+    }
+
+    /// Read the metadata header without checking the values it declares
+    fn read_metadata(&self) -> Result<BdlMetadata, BdlError> {
         let mut metadata = BdlMetadata::default();
+        let mut in_changelog = false;
         
         // Split content into lines and process each line
-        for line in self.content.lines() {
+        for (index, line) in self.logical_lines()?.iter().enumerate() {
             let line = line.trim();
+
+            // An optional `#!bdl <version>` shebang may only appear first
+            if index == 0 && line.starts_with("#!") {
+                let version = parse_format_version(line).map_err(|e| e.at(Span { line: 1, column: 1 }))?;
+                metadata.format_version = Some(version);
+                continue;
+            }
             
             // Stop at first non-metadata line
             if !line.starts_with('#') || line.is_empty() {
                 break;
             }
 
+            // Collect `# - entry` lines following a `# Changelog:` header
+            let body = line.trim_start_matches('#').trim();
+            if let Some(entry) = body.strip_prefix('-') {
+                if in_changelog {
+                    metadata.changelog.get_or_insert_with(Vec::new).push(entry.trim().to_string());
+                }
+                continue;
+            }
+            in_changelog = false;
+
             // Skip comment lines that don't contain metadata
             if !line.contains(':') {
                 continue;
@@ -85,6 +364,10 @@ impl BdlParser {
                     "description" => metadata.description = Some(value.to_string()),
                     "author" => metadata.author = Some(value.to_string()),
                     "version" => metadata.version = Some(value.to_string()),
+                    "changelog" => {
+                        in_changelog = true;
+                        metadata.changelog.get_or_insert_with(Vec::new);
+                    },
                     "required" => {
                         metadata.required = Some(
                             value.split(',')
@@ -100,236 +383,1165 @@ impl BdlParser {
         Ok(metadata)
     }
 
+    /// Check the metadata `Version` against `strict_version`
+    fn check_version(&self, metadata: &BdlMetadata) -> Result<(), BdlError> {
+        if self.options.strict_version {
+            if let Some(version) = &metadata.version {
+                if !is_valid_semver(version) {
+                    return Err(BdlError::ParseError(format!("Invalid semver version: {}", version).into()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Parse variable declarations (both global and local)
-    pub fn parse_variables(&self) -> Result<(Option<HashMap<String, BdlValue>>, HashMap<String, BdlValue>), BdlError> {
-        let mut global_vars = None;
+    pub fn parse_variables(&self) -> Result<ParsedVariables, BdlError> {
+        let mut at = None;
+        let mut recovered = Vec::new();
+        let result = self.parse_variables_at(&mut at, &mut recovered).map_err(|e| locate(e, at));
+        first_error(result, recovered)
+    }
+
+    /// `parse_variables`, recording the position of the line being read in `at`
+    ///
+    /// Errors confined to a single line are pushed to `recovered` and the line
+    /// is skipped.
+    fn parse_variables_at(&self, at: &mut Option<Span>, recovered: &mut Vec<BdlError>) -> Result<ParsedVariables, BdlError> {
+        let mut global_vars: Option<HashMap<String, BdlValue>> = None;
         let mut local_vars = HashMap::new();
-        let mut in_vars_block = false;
-        let mut current_block: Option<&mut HashMap<String, BdlValue>> = None;
+        let mut open_block: Option<VariableScope> = None;
 
-        for line in self.content.lines() {
+        for (number, line) in self.numbered_lines()? {
+            *at = Some(line_span(number, &line));
             let line = line.trim();
 
-            // Skip empty lines and comments
-            if line.is_empty() || (line.starts_with('#') && !line.contains('$')) {
+            // Skip empty lines and comments, including comments inside a block
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
 
             // Check for variable block start
-            if line.starts_with("$global_vars:") {
-                if global_vars.is_some() {
-                    return Err(BdlError::ParseError("Duplicate global variables declaration".to_string()));
+            let block_start = if let Some(rest) = line.strip_prefix("$global_vars:") {
+                Some((VariableScope::Global, rest))
+            } else {
+                line.strip_prefix("$local_vars:").map(|rest| (VariableScope::Local, rest))
+            };
+
+            if let Some((scope, rest)) = block_start {
+                // A new block cannot start while another is still open
+                if let Some(open) = open_block {
+                    return Err(BdlError::ParseError(format!("Unterminated {} block", open.block_name()).into()));
+                }
+
+                if scope == VariableScope::Global {
+                    if global_vars.is_some() {
+                        return Err(BdlError::ParseError("Duplicate global variables declaration".into()));
+                    }
+                    if let Some(filename) = &self.filename {
+                        if !self.is_main_file() {
+                            return Err(BdlError::VariableError(
+                                format!("Global variables can only be declared in main.bdl, found in {}", filename).into()
+                            ));
+                        }
+                    }
+                    global_vars = Some(HashMap::new());
+                }
+
+                // `$local_vars: {}` opens and closes on the same line
+                if rest.trim() != "{}" {
+                    open_block = Some(scope);
                 }
-                global_vars = Some(HashMap::new());
-                current_block = global_vars.as_mut();
-                in_vars_block = true;
-                continue;
-            } else if line.starts_with("$local_vars:") {
-                current_block = Some(&mut local_vars);
-                in_vars_block = true;
                 continue;
             }
 
             // Parse variables within a block
-            if in_vars_block {
+            if let Some(scope) = open_block {
                 if line == "}" {
-                    in_vars_block = false;
-                    current_block = None;
+                    open_block = None;
                     continue;
                 }
 
-                if let Some(block) = &mut current_block {
-                    if let Some((key, value)) = parse_variable_line(line)? {
-                        block.insert(key, value);
+                let parsed = match parse_variable_line(line) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        recovered.push(locate(e, *at));
+                        continue;
+                    }
+                };
+                if let Some((key, value)) = parsed {
+                    let block = match scope {
+                        VariableScope::Global => global_vars.get_or_insert_with(HashMap::new),
+                        VariableScope::Local => &mut local_vars,
+                    };
+                    if block.contains_key(&key) {
+                        recovered.push(locate(BdlError::VariableError(
+                            format!("Duplicate variable '{}' in {} block", key, scope.block_name()).into()
+                        ), *at));
+                        continue;
                     }
+                    block.insert(key, value);
                 }
             }
         }
 
+        *at = None;
+        if let Some(open) = open_block {
+            return Err(BdlError::ParseError(format!("Unterminated {} block", open.block_name()).into()));
+        }
+
         Ok((global_vars, local_vars))
     }
 
     /// Parse all nodes from the content
     pub fn parse_nodes(&self, dependencies: &HashSet<String>) -> Result<HashMap<String, BdlNode>, BdlError> {
+        let mut at = None;
+        let mut recovered = Vec::new();
+        let result = self.parse_nodes_at(dependencies, &mut at, &mut recovered).map_err(|e| locate(e, at));
+        first_error(result, recovered)
+    }
+
+    /// `parse_nodes`, recording the position of the line being read in `at`
+    ///
+    /// Every error is pushed to `recovered`. Bad options, text lines,
+    /// directives and `%data` blocks are skipped, as are nodes with an invalid
+    /// or duplicate header together with their content.
+    fn parse_nodes_at(
+        &self,
+        dependencies: &HashSet<String>,
+        at: &mut Option<Span>,
+        recovered: &mut Vec<BdlError>,
+    ) -> Result<HashMap<String, BdlNode>, BdlError> {
         let mut nodes = HashMap::new();
+        let mut gated_out = HashSet::new();
         let mut current_node: Option<BdlNode> = None;
+        // Text lines are buffered so a paragraph becomes one run of elements
         let mut current_content = Vec::new();
+        // Blank lines seen since the last line of the buffered paragraph
+        let mut blank_lines = 0;
+        let mut anonymous_count = 0;
 
-        for line in self.content.lines() {
-            let line = line.trim();
+        let lines = self.numbered_lines()?;
+        let mut lines = lines.iter();
+
+        while let Some((number, raw_line)) = lines.next() {
+            *at = Some(line_span(*number, raw_line));
+            let line = raw_line.trim();
             
             // Skip empty lines and comments
             if line.is_empty() || line.starts_with('#') {
+                if line.is_empty() && !current_content.is_empty() {
+                    blank_lines += 1;
+                }
+                continue;
+            }
+            let paragraph_breaks = std::mem::take(&mut blank_lines);
+
+            // Unnamed node, linked from a preceding unnamed node with no options
+            if self.options.auto_name_anonymous && line == ANONYMOUS_NODE_MARKER {
+                let name = format!("{}{}", AUTO_NODE_PREFIX, anonymous_count);
+                anonymous_count += 1;
+                let duplicate = nodes.contains_key(&name) || gated_out.contains(&name);
+
+                flush_text(&mut current_node, &mut current_content);
+                if let Some(mut node) = current_node.take() {
+                    if node.name.starts_with(AUTO_NODE_PREFIX) && node.options.is_empty() && !duplicate {
+                        node.options.push(BdlBranchOption {
+                            keywords: Vec::new(),
+                            destination: BdlDestination::Node(name.clone()),
+                            condition: None,
+                            match_all: false,
+                            doc: None,
+                            order: None,
+                            enabled: None,
+                        });
+                    }
+                    nodes.insert(node.name.clone(), node);
+                }
+                if duplicate {
+                    recovered.push(locate(BdlError::NodeError(format!("Duplicate node name: {}", name).into()), *at));
+                    continue;
+                }
+                current_node = Some(BdlNode::new(name));
                 continue;
             }
 
             // Check for node start
-            if line.starts_with('@') {
+            if let Some(header) = line.strip_prefix('@') {
                 // Save previous node if it exists
+                flush_text(&mut current_node, &mut current_content);
                 if let Some(node) = current_node.take() {
                     nodes.insert(node.name.clone(), node);
                 }
 
                 // Start new node
-                let name = line[1..].trim().to_string();
-                if nodes.contains_key(&name) {
-                    return Err(BdlError::NodeError(format!("Duplicate node name: {}", name)));
+                let NodeHeader { name, gate, tags } = match parse_node_header(header) {
+                    Ok(header) => header,
+                    Err(e) => {
+                        // Content up to the next header is dropped with the node
+                        recovered.push(locate(e, *at));
+                        continue;
+                    }
+                };
+                if nodes.contains_key(&name) || gated_out.contains(&name) {
+                    // Content up to the next header is dropped with the node
+                    recovered.push(locate(BdlError::NodeError(format!("Duplicate node name: {}", name).into()), *at));
+                    continue;
                 }
-                current_node = Some(BdlNode::new(name));
-                current_content.clear();
+
+                // Skip gated nodes whose flag is not enabled
+                if let Some(flag) = gate {
+                    if !self.options.enabled_flags.contains(&flag) {
+                        gated_out.insert(name);
+                        continue;
+                    }
+                }
+                current_node = Some(BdlNode { tags, ..BdlNode::new(name) });
                 continue;
             }
 
             // Process node content if we're in a node
             if let Some(ref mut node) = current_node {
-                if line.starts_with('{') || line.starts_with("?{") {
+                if line.starts_with('{') || line.starts_with("?{") || line.starts_with("~{") {
                     // Parse option line
-                    let option = self.parse_option(line, dependencies)?;
+                    let option = match self.parse_option(line, dependencies) {
+                        Ok(option) => option,
+                        Err(e) => {
+                            recovered.push(locate(e, *at));
+                            continue;
+                        }
+                    };
+                    node.content.append(&mut current_content);
                     node.options.push(option);
+                } else if let Some(block) = line.strip_prefix("%data").filter(|rest| rest.starts_with(char::is_whitespace)) {
+                    node.content.append(&mut current_content);
+                    match parse_data_block(block, &mut lines.by_ref().map(|(_, line)| line)) {
+                        Ok(element) => node.content.push(element),
+                        Err(e) => recovered.push(locate(e, *at)),
+                    }
+                } else if let Some(directive) = line.strip_prefix('%') {
+                    node.content.append(&mut current_content);
+                    match self.parse_directive(directive) {
+                        Ok(element) => node.content.push(element),
+                        Err(e) => recovered.push(locate(e, *at)),
+                    }
                 } else {
                     // Add content line
-                    current_content.push(line.to_string());
-                    if !current_content.is_empty() {
-                        node.content.push(BdlContentElement::Text(current_content.join("\n")));
-                        current_content.clear();
+                    let text = if self.options.preserve_indentation {
+                        raw_line.trim_end()
+                    } else {
+                        line
+                    };
+                    if self.options.preserve_paragraph_breaks {
+                        for _ in 0..paragraph_breaks {
+                            push_text_line(&mut current_content, Vec::new());
+                        }
+                    }
+                    match tokenize_text(text) {
+                        Ok(elements) => push_text_line(&mut current_content, elements),
+                        Err(e) => recovered.push(locate(e, *at)),
                     }
                 }
             }
         }
 
         // Save last node if it exists
+        flush_text(&mut current_node, &mut current_content);
         if let Some(node) = current_node {
             nodes.insert(node.name.clone(), node);
         }
+        *at = None;
+
+        self.apply_gated_node_policy(&mut nodes, &gated_out, recovered);
 
         Ok(nodes)
     }
 
+    /// Drop or reject options that target nodes removed by an `#if` gate
+    ///
+    /// Under `GatedNodePolicy::Error` one error per offending node is pushed
+    /// to `errors`, in node name order.
+    fn apply_gated_node_policy(
+        &self,
+        nodes: &mut HashMap<String, BdlNode>,
+        gated_out: &HashSet<String>,
+        errors: &mut Vec<BdlError>,
+    ) {
+        if gated_out.is_empty() {
+            return;
+        }
+
+        let mut nodes: Vec<&mut BdlNode> = nodes.values_mut().collect();
+        nodes.sort_by(|a, b| a.name.cmp(&b.name));
+        for node in nodes {
+            let targets_gated = |option: &BdlBranchOption| {
+                matches!(&option.destination, BdlDestination::Node(target) if gated_out.contains(target))
+            };
+
+            match self.options.gated_node_policy {
+                GatedNodePolicy::Drop => node.options.retain(|option| !targets_gated(option)),
+                GatedNodePolicy::Error => {
+                    if let Some(option) = node.options.iter().find(|option| targets_gated(option)) {
+                        errors.push(BdlError::NodeError(format!(
+                            "Node '{}' has an option targeting gated-out node: {:?}",
+                            node.name, option.destination
+                        ).into()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parse a `%tag args` directive line (without the leading '%')
+    fn parse_directive(&self, directive: &str) -> Result<BdlContentElement, BdlError> {
+        let (tag, args) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+        if tag.is_empty() {
+            return Err(BdlError::ParseError(format!("Missing directive name: %{}", directive).into()));
+        }
+
+        match self.directives.get(tag) {
+            Some(handler) => Ok(BdlContentElement::Custom {
+                tag: tag.to_string(),
+                data: handler(args.trim())?,
+            }),
+            None => match self.options.unknown_directive_policy {
+                UnknownDirectivePolicy::PassThrough => Ok(BdlContentElement::Text(format!("%{}", directive))),
+                UnknownDirectivePolicy::Error => {
+                    Err(BdlError::ParseError(format!("Unknown directive: %{}", tag).into()))
+                }
+            },
+        }
+    }
+
     /// Parse a single option line
+    ///
+    /// The general form is `?{condition} {keyword, ...} -> destination`, where
+    /// the condition is optional and the keyword list may be left out when a
+    /// condition is given. The condition may instead trail the destination,
+    /// as in `{jump} -> @ledge ?{has_rope}`, but not appear in both places.
+    /// Keywords marked `+`, as in `{+open +door}`, must all appear in the
+    /// input. A bare `{exit}` ends the dialog, `-> @self` (or `-> @.`)
+    /// re-enters the current node, and `-> !{function}` goes wherever the
+    /// named host function decides at runtime. Empty keywords, as in
+    /// `{,yes}`, are rejected. A trailing `/// text` is kept as the
+    /// option's `doc`, and an `@n` right after the keyword list, as in
+    /// `{yes}@1`, sets its display order. A `~{condition}` after any
+    /// `?{condition}` leaves the option listed but disabled while it fails.
     fn parse_option(&self, line: &str, dependencies: &HashSet<String>) -> Result<BdlBranchOption, BdlError> {
-        // TODO: Implement option parsing
-        unimplemented!("Option parsing not yet implemented")
+        let (line, doc) = split_doc_comment(line);
+        let invalid = |reason: &str| BdlError::ParseError(format!("Invalid option '{}': {}", line, reason).into());
+        let condition_from = |expression: &str| {
+            parse_condition(expression).map_err(|e| match e {
+                BdlError::ParseError(reason) => invalid(&reason),
+                other => other,
+            })
+        };
+        let mut rest = line.trim();
+
+        if rest == "{exit}" {
+            return Ok(BdlBranchOption {
+                keywords: Vec::new(),
+                destination: BdlDestination::Exit,
+                condition: None,
+                match_all: false,
+                doc,
+                order: None,
+                enabled: None,
+            });
+        }
+
+        // Optional `?{variable}` or `?{variable <op> value}` condition
+        let mut condition = None;
+        if let Some(after) = rest.strip_prefix("?{") {
+            let (expression, after) = after.split_once('}').ok_or_else(|| invalid("unterminated condition"))?;
+            condition = Some(condition_from(expression)?);
+            rest = after.trim_start();
+        }
+
+        // Optional `~{...}` condition that enables the option without hiding it
+        let mut enabled = None;
+        if let Some(after) = rest.strip_prefix("~{") {
+            let (expression, after) = after.split_once('}').ok_or_else(|| invalid("unterminated enabling condition"))?;
+            enabled = Some(condition_from(expression)?);
+            rest = after.trim_start();
+        }
+
+        // Keyword list, required unless a condition is present
+        let mut keywords = Vec::new();
+        let mut match_all = false;
+        let mut order = None;
+        if let Some(after) = rest.strip_prefix('{') {
+            let (list, mut after) = after.split_once('}').ok_or_else(|| invalid("unterminated keyword list"))?;
+            match_all = list.trim_start().starts_with('+');
+            keywords = if match_all {
+                parse_required_keywords(list).map_err(&invalid)?
+            } else {
+                parse_keywords(list).map_err(&invalid)?
+            };
+
+            // Display order annotation directly after the list: `{yes}@1`
+            if let Some(annotation) = after.strip_prefix('@') {
+                // A leading '-' is a sign; a later one starts the `->`
+                let end = annotation
+                    .char_indices()
+                    .find(|&(index, c)| c.is_whitespace() || (c == '-' && index > 0))
+                    .map_or(annotation.len(), |(index, _)| index);
+                order = Some(annotation[..end].parse().map_err(|_| invalid("invalid display order"))?);
+                after = &annotation[end..];
+            }
+            rest = after.trim_start();
+        } else if condition.is_none() {
+            return Err(invalid("missing keyword list"));
+        }
+
+        let mut target = rest.strip_prefix("->").ok_or_else(|| invalid("missing '->'"))?.trim();
+        if let Some((before, trailing)) = target.split_once("?{") {
+            if condition.is_some() {
+                return Err(invalid("condition given both before and after the destination"));
+            }
+            let expression = trailing.strip_suffix('}').ok_or_else(|| invalid("unterminated condition"))?;
+            condition = Some(condition_from(expression)?);
+            target = before.trim();
+        }
+        let destination = self.parse_destination(target, dependencies).map_err(|e| match e {
+            BdlError::ParseError(reason) => invalid(&reason),
+            other => other,
+        })?;
+
+        Ok(BdlBranchOption { keywords, destination, condition, match_all, doc, order, enabled })
+    }
+
+    /// Parse the part of an option after `->`
+    fn parse_destination(&self, target: &str, dependencies: &HashSet<String>) -> Result<BdlDestination, BdlError> {
+        if target == "exit" {
+            return Ok(BdlDestination::Exit);
+        }
+        // Names the node the option is in, so `self` cannot be a node name
+        if target == "@self" || target == "@." {
+            return Ok(BdlDestination::Current);
+        }
+
+        if let Some(inner) = target.strip_prefix("!{") {
+            let function = inner
+                .strip_suffix('}')
+                .map(str::trim)
+                .filter(|function| is_identifier(function))
+                .ok_or_else(|| BdlError::ParseError(format!("malformed computed destination {}", target).into()))?;
+            return Ok(BdlDestination::Computed { function: function.to_string() });
+        }
+
+        if let Some(inner) = target.strip_prefix('[') {
+            let (file, node) = inner
+                .strip_suffix(']')
+                .and_then(|inner| inner.split_once(':'))
+                .map(|(file, node)| (file.trim(), node.trim()))
+                .filter(|(file, node)| !file.is_empty() && is_valid_node_name(node))
+                .ok_or_else(|| BdlError::ParseError(format!("malformed file transfer {}", target).into()))?;
+            self.validate_file_transfer(file, dependencies)?;
+            return Ok(BdlDestination::FileTransfer {
+                file: file.to_string(),
+                node: node.to_string(),
+            });
+        }
+
+        // Local nodes may be written with or without the `@`
+        let name = target.strip_prefix('@').unwrap_or(target);
+        if !is_valid_node_name(name) {
+            return Err(BdlError::ParseError(format!("invalid destination '{}'", target).into()));
+        }
+        Ok(BdlDestination::Node(name.to_string()))
     }
 }
 
-/// Parse a single variable declaration line
-fn parse_variable_line(line: &str) -> Result<Option<(String, BdlValue)>, BdlError> {
-    // Skip empty lines and closing braces
-    if line.trim().is_empty() || line.trim() == "}" {
-        return Ok(None);
+/// Collect a dependency list into a set, rejecting a file listed twice
+fn unique_dependencies(dependencies: &[String]) -> Result<HashSet<String>, BdlError> {
+    let mut unique = HashSet::new();
+    for dep in dependencies {
+        // A dependency listed twice is almost certainly a typo
+        if !unique.insert(dep.clone()) {
+            return Err(BdlError::DependencyError(format!("Duplicate dependency: {}", dep).into()));
+        }
     }
 
-    // Split key and value
-    let parts: Vec<&str> = line.split(':').collect();
-    if parts.len() != 2 {
-        return Err(BdlError::ParseError(format!("Invalid variable declaration: {}", line)));
-    }
-
-    let key = parts[0].trim().to_string();
-    let value = parts[1].trim().trim_matches(',').trim();
-
-    // Parse the value based on its format
-    let parsed_value = if value.starts_with('"') && value.ends_with('"') {
-        BdlValue::String(value.trim_matches('"').to_string())
-    } else if value == "true" {
-        BdlValue::Boolean(true)
-    } else if value == "false" {
-        BdlValue::Boolean(false)
-    } else if value.parse::<f64>().is_ok() {
-        BdlValue::Number(value.parse::<f64>().unwrap())
-    } else if value.is_empty() || value == "{}" {
-        BdlValue::Empty
-    } else {
-        return Err(BdlError::ParseError(format!("Invalid value format: {}", value)));
-    };
+    Ok(unique)
+}
 
-    Ok(Some((key, parsed_value)))
+/// Position of the first non-blank character of a line
+fn line_span(line: usize, text: &str) -> Span {
+    let indent = text.len() - text.trim_start().len();
+    Span { line, column: text[..indent].chars().count() + 1 }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Report the first error a strict parse stage ran into
+///
+/// Recovered errors come from lines before the one a fatal error stopped on,
+/// so they take precedence.
+fn first_error<T>(result: Result<T, BdlError>, recovered: Vec<BdlError>) -> Result<T, BdlError> {
+    match recovered.into_iter().next() {
+        Some(error) => Err(error),
+        None => result,
+    }
+}
 
-    #[test]
-    fn test_parse_empty_metadata() {
-        let parser = BdlParser::new("".to_string());
-        let metadata = parser.parse_metadata().unwrap();
-        assert!(metadata.topic.is_none());
-        assert!(metadata.description.is_none());
-        assert!(metadata.author.is_none());
-        assert!(metadata.version.is_none());
-        assert!(metadata.required.is_none());
+/// Tag an error with the line a parse stage stopped on, if any
+fn locate(error: BdlError, at: Option<Span>) -> BdlError {
+    match at {
+        Some(span) => error.at(span),
+        None => error,
     }
+}
 
-    #[test]
-    fn test_parse_complete_metadata() {
-        let content = "\
-# Topic: Test Dialog
-# Description: A test dialog file
-# Author: Test Author
-# Version: 1.0
-# Required: mod1.bdl, mod2.bdl
+/// Parse a `%data <mime> { ... }` block, pulling further lines until the braces balance
+///
+/// `header` is the text after `%data`. The body between the outer braces is
+/// kept verbatim; braces inside double-quoted strings are not counted.
+fn parse_data_block<'a>(header: &str, lines: &mut impl Iterator<Item = &'a String>) -> Result<BdlContentElement, BdlError> {
+    let (mime, first) = header
+        .split_once('{')
+        .ok_or_else(|| BdlError::ParseError(format!("Expected '{{' after %data{}", header).into()))?;
+    let mime = mime.trim();
+    if mime.is_empty() {
+        return Err(BdlError::ParseError("Missing MIME type in %data block".into()));
+    }
 
-@start
-Some content...";
+    let mut body = String::new();
+    let mut depth = 1;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut segment = first.to_string();
 
-        let parser = BdlParser::new(content.to_string());
-        let metadata = parser.parse_metadata().unwrap();
-        
-        assert_eq!(metadata.topic, Some("Test Dialog".to_string()));
-        assert_eq!(metadata.description, Some("A test dialog file".to_string()));
-        assert_eq!(metadata.author, Some("Test Author".to_string()));
-        assert_eq!(metadata.version, Some("1.0".to_string()));
-        assert_eq!(metadata.required, Some(vec!["mod1.bdl".to_string(), "mod2.bdl".to_string()]));
+    loop {
+        for (index, c) in segment.char_indices() {
+            if in_string {
+                match c {
+                    _ if escaped => escaped = false,
+                    '\\' => escaped = true,
+                    '"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match c {
+                '"' => in_string = true,
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        body.push_str(&segment[..index]);
+                        let trailing = segment[index + 1..].trim();
+                        if !trailing.is_empty() {
+                            return Err(BdlError::ParseError(format!("Unexpected text after %data block: {}", trailing).into()));
+                        }
+                        return Ok(BdlContentElement::Data {
+                            mime: mime.to_string(),
+                            body,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        body.push_str(&segment);
+        body.push('\n');
+        segment = match lines.next() {
+            Some(line) => line.clone(),
+            None => return Err(BdlError::ParseError(format!("Unterminated %data {} block", mime).into())),
+        };
     }
+}
 
-    #[test]
-    fn test_parse_partial_metadata() {
-        let content = "\
-# Topic: Partial Test
-# Author: Test Author
+/// A node header split into its parts
+struct NodeHeader {
+    name: String,
+    /// Flag from an `#if <flag>` annotation
+    gate: Option<String>,
+    /// Single-word `#tag` annotations, plus `#allow-unreachable`
+    tags: Vec<String>,
+}
 
-@start";
+/// Parse a node header (the text after '@'): a name followed by `#tag` and `#if <flag>` annotations
+fn parse_node_header(header: &str) -> Result<NodeHeader, BdlError> {
+    let mut parts = header.split('#');
+    let name = parts.next().unwrap_or_default().trim().to_string();
+    let mut gate = None;
+    let mut tags = Vec::new();
 
-        let parser = BdlParser::new(content.to_string());
-        let metadata = parser.parse_metadata().unwrap();
-        
-        assert_eq!(metadata.topic, Some("Partial Test".to_string()));
-        assert!(metadata.description.is_none());
-        assert_eq!(metadata.author, Some("Test Author".to_string()));
-        assert!(metadata.version.is_none());
-        assert!(metadata.required.is_none());
+    for annotation in parts {
+        let annotation = annotation.trim();
+        match annotation.strip_prefix("if ") {
+            Some(flag) if gate.is_none() && !flag.trim().is_empty() => gate = Some(flag.trim().to_string()),
+            None if (is_identifier(annotation) && annotation != "if") || annotation == ALLOW_UNREACHABLE_TAG => {
+                tags.push(annotation.to_string())
+            }
+            _ => return Err(BdlError::ParseError(format!("Invalid node annotation: #{}", annotation).into())),
+        }
     }
 
-    #[test]
-    fn test_parse_metadata_with_comments() {
-        let content = "\
-# This is a comment
-# Topic: With Comments
-# Another comment
-# Author: Test Author
-# Description: Test Description
-# Yet another comment
+    Ok(NodeHeader { name, gate, tags })
+}
 
-Content starts here...";
+/// Append one line's elements to a buffered paragraph
+///
+/// The line is joined to the previous one with a newline, and adjacent
+/// `Text` elements are merged.
+fn push_text_line(paragraph: &mut Vec<BdlContentElement>, line: Vec<BdlContentElement>) {
+    let separator = (!paragraph.is_empty()).then(|| BdlContentElement::Text("\n".to_string()));
 
-        let parser = BdlParser::new(content.to_string());
-        let metadata = parser.parse_metadata().unwrap();
-        
-        assert_eq!(metadata.topic, Some("With Comments".to_string()));
-        assert_eq!(metadata.description, Some("Test Description".to_string()));
-        assert_eq!(metadata.author, Some("Test Author".to_string()));
-        assert!(metadata.version.is_none());
-        assert!(metadata.required.is_none());
+    for element in separator.into_iter().chain(line) {
+        match (paragraph.last_mut(), element) {
+            (Some(BdlContentElement::Text(last)), BdlContentElement::Text(text)) => last.push_str(&text),
+            (_, element) => paragraph.push(element),
+        }
     }
+}
 
-    #[test]
-    fn test_parse_empty_variables() {
-        let content = "# Empty file";
-        let parser = BdlParser::new(content.to_string());
-        let (global, local) = parser.parse_variables().unwrap();
-        assert!(global.is_none());
-        assert!(local.is_empty());
+/// Move a buffered paragraph into the node being built, if there is one
+fn flush_text(node: &mut Option<BdlNode>, paragraph: &mut Vec<BdlContentElement>) {
+    match node {
+        Some(node) => node.content.append(paragraph),
+        None => paragraph.clear(),
+    }
+}
+
+/// Split a line of text into `Text`, `${variable}` and `!{function}` elements, in order
+///
+/// A variable may carry a format spec, `${price:.2}`, checked here, and
+/// `${count == 1 ? "apple" : "apples"}` picks between two quoted strings.
+/// Function calls may take arguments, `!{add(score, 10)}`, and name their
+/// result variables either inline as `!{fetch -> a, b}` or with the
+/// `!{fetch} : ~{a} ~{b}` form from the specification. `\$`, `\!` and
+/// `\\` stand for a literal `$`, `!` or `\`. Empty text between adjacent
+/// markers is not emitted.
+fn tokenize_text(line: &str) -> Result<Vec<BdlContentElement>, BdlError> {
+    let mut elements = Vec::new();
+    let mut rest = line;
+
+    while let Some(start) = find_marker(rest) {
+        if start > 0 {
+            elements.push(BdlContentElement::Text(unescape_text(&rest[..start])));
+        }
+
+        let marker = &rest[start..start + 2];
+        let after = &rest[start + 2..];
+        let end = find_closing(after, '{', '}')
+            .ok_or_else(|| BdlError::ParseError(format!("Unterminated '{}' in line: {}", marker, line).into()))?;
+        let inner = after[..end].trim();
+        rest = &after[end + 1..];
+
+        if marker == "${" {
+            if inner.is_empty() {
+                return Err(BdlError::ParseError(format!("Empty variable reference in line: {}", line).into()));
+            }
+            if inner.contains('?') {
+                elements.push(parse_inline_conditional(inner, line)?);
+                continue;
+            }
+            match inner.split_once(':') {
+                Some((name, spec)) => elements.push(BdlContentElement::FormattedVariable {
+                    name: name.trim().to_string(),
+                    spec: parse_format_spec(spec.trim())
+                        .map_err(|e| BdlError::ParseError(format!("{} in line: {}", e, line).into()))?,
+                }),
+                None => elements.push(BdlContentElement::Variable(inner.to_string())),
+            }
+            continue;
+        }
+
+        // An argument list is only recognised before the `->`
+        let (call, bindings) = match inner.find('(').filter(|&open| !inner[..open].contains("->")) {
+            Some(open) => {
+                let close = find_closing(&inner[open + 1..], '(', ')')
+                    .map(|close| open + 1 + close)
+                    .ok_or_else(|| BdlError::ParseError(format!("Unterminated argument list in line: {}", line).into()))?;
+                (&inner[..=close], inner[close + 1..].trim())
+            }
+            None => match inner.find("->") {
+                Some(arrow) => (&inner[..arrow], &inner[arrow..]),
+                None => (inner, ""),
+            },
+        };
+        let mut result_vars = match bindings.strip_prefix("->") {
+            Some(vars) => vars.split(',').map(|v| v.trim().to_string()).collect(),
+            None if bindings.is_empty() => Vec::new(),
+            None => return Err(BdlError::ParseError(format!("Expected '->' after arguments in line: {}", line).into())),
+        };
+
+        let (name, args) = match call.split_once('(') {
+            Some((name, args)) => (name.trim(), parse_arguments(&args[..args.len() - 1], line)?),
+            None => (call.trim(), Vec::new()),
+        };
+        if !is_identifier(name) {
+            return Err(BdlError::ParseError(format!("Invalid function name '{}' in line: {}", name, line).into()));
+        }
+        if result_vars.is_empty() {
+            if let Some((vars, after)) = parse_result_bindings(rest) {
+                result_vars = vars;
+                rest = after;
+            }
+        }
+        if result_vars.iter().any(|v| v.is_empty()) {
+            return Err(BdlError::ParseError(format!("Empty result variable in line: {}", line).into()));
+        }
+        elements.push(BdlContentElement::FunctionCall {
+            name: name.to_string(),
+            args,
+            result_vars,
+        });
+    }
+
+    if !rest.is_empty() {
+        elements.push(BdlContentElement::Text(unescape_text(rest)));
+    }
+    Ok(elements)
+}
+
+/// Position of the `close` matching an already opened `open`
+///
+/// Nested pairs and anything inside double-quoted strings are skipped, so
+/// arguments like `"a)"` or `${var}` do not end the construct early.
+fn find_closing(s: &str, open: char, close: char) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == open {
+            depth += 1;
+        } else if c == close {
+            match depth.checked_sub(1) {
+                Some(inner) => depth = inner,
+                None => return Some(i),
+            }
+        }
+    }
+    None
+}
+
+/// Parse the comma-separated arguments of a function call
+///
+/// `${var}` and bare identifiers other than `true` and `false` refer to
+/// variables; anything else must be a literal value.
+fn parse_arguments(list: &str, line: &str) -> Result<Vec<BdlArg>, BdlError> {
+    let parts = crate::split_top_level(list, ',')
+        .map_err(|_| BdlError::ParseError(format!("Malformed argument list in line: {}", line).into()))?;
+
+    parts
+        .into_iter()
+        .map(|part| {
+            let part = part.trim();
+            if part.is_empty() {
+                return Err(BdlError::ParseError(format!("Empty argument in line: {}", line).into()));
+            }
+            if let Some(name) = part.strip_prefix("${").and_then(|p| p.strip_suffix('}')) {
+                let name = name.trim();
+                if !is_identifier(name) {
+                    return Err(BdlError::ParseError(format!("Invalid variable argument '{}' in line: {}", part, line).into()));
+                }
+                return Ok(BdlArg::Variable(name.to_string()));
+            }
+            if is_identifier(part) && part != "true" && part != "false" {
+                return Ok(BdlArg::Variable(part.to_string()));
+            }
+            BdlValue::parse(part).map(BdlArg::Value)
+        })
+        .collect()
+}
+
+/// Parse the inside of `${condition ? "then" : "otherwise"}`
+fn parse_inline_conditional(expression: &str, line: &str) -> Result<BdlContentElement, BdlError> {
+    let invalid = |reason: &str| {
+        BdlError::ParseError(format!("Invalid inline conditional '{}' in line: {}: {}", expression, line, reason).into())
+    };
+    let split = |s, separator| crate::split_top_level(s, separator).map_err(|e| invalid(e.message()));
+
+    let [condition, branches] = split(expression, '?')?[..] else {
+        return Err(invalid("expected one '?'"));
+    };
+    let [then, otherwise] = split(branches, ':')?[..] else {
+        return Err(invalid("expected one ':' between the branches"));
+    };
+    let text = |branch: &str| match BdlValue::parse(branch.trim()) {
+        Ok(BdlValue::String(text)) => Ok(text),
+        _ => Err(invalid(&format!("branch {} is not a quoted string", branch.trim()))),
+    };
+
+    Ok(BdlContentElement::Conditional {
+        condition: parse_condition(condition).map_err(|e| match e {
+            BdlError::ParseError(reason) => invalid(&reason),
+            other => other,
+        })?,
+        then: text(then)?,
+        otherwise: text(otherwise)?,
+    })
+}
+
+/// Position of the next `${` or `!{` marker that is not escaped
+///
+/// A marker is escaped by an odd number of backslashes, so `\\${a}` is a
+/// literal backslash followed by a variable.
+fn find_marker(s: &str) -> Option<usize> {
+    s.match_indices(['$', '!'])
+        .map(|(i, _)| i)
+        .find(|&i| {
+            let backslashes = s[..i].chars().rev().take_while(|&c| c == '\\').count();
+            s[i + 1..].starts_with('{') && backslashes % 2 == 0
+        })
+}
+
+/// Turn `\\`, `\$` and `\!` back into the literal characters
+///
+/// A backslash before any other character is kept as is.
+fn unescape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.clone().next() {
+            Some(escaped @ ('\\' | '$' | '!')) => {
+                out.push(escaped);
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Parse a ` : ~{a} ~{b}` binding list following a function call
+///
+/// Returns the variable names and the remaining text, or `None` if `s` does
+/// not start with at least one binding.
+fn parse_result_bindings(s: &str) -> Option<(Vec<String>, &str)> {
+    let mut rest = s.trim_start().strip_prefix(':')?;
+    let mut vars = Vec::new();
+    while let Some(after) = rest.trim_start().strip_prefix("~{") {
+        let (var, after) = after.split_once('}')?;
+        vars.push(var.trim().to_string());
+        rest = after;
+    }
+    (!vars.is_empty()).then_some((vars, rest))
+}
+
+/// Identifiers start with a letter or underscore, then letters, digits or underscores
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Parse the `spec` of `${var:spec}`: an optional `0`, a width, and an optional `.precision`
+fn parse_format_spec(spec: &str) -> Result<FormatSpec, String> {
+    let invalid = || format!("Invalid format spec '{}'", spec);
+    let number = |digits: &str| {
+        if digits.bytes().all(|b| b.is_ascii_digit()) {
+            digits.parse::<usize>().map_err(|_| invalid())
+        } else {
+            Err(invalid())
+        }
+    };
+    if spec.is_empty() {
+        return Err(invalid());
+    }
+
+    let (width, precision) = match spec.split_once('.') {
+        Some((width, precision)) => (width, Some(number(precision)?)),
+        None => (spec, None),
+    };
+    let zero_pad = width.starts_with('0');
+    let width = &width[usize::from(zero_pad)..];
+    Ok(FormatSpec {
+        zero_pad,
+        width: if width.is_empty() { None } else { Some(number(width)?) },
+        precision,
+    })
+}
+
+/// Parse the inside of `?{...}`: a variable, optionally compared with a literal
+fn parse_condition(expression: &str) -> Result<BdlCondition, BdlError> {
+    const OPERATORS: [(&str, BdlCompareOp); 6] = [
+        ("==", BdlCompareOp::Eq),
+        ("!=", BdlCompareOp::Ne),
+        (">=", BdlCompareOp::Ge),
+        ("<=", BdlCompareOp::Le),
+        (">", BdlCompareOp::Gt),
+        ("<", BdlCompareOp::Lt),
+    ];
+
+    // The first operator from the left wins, so operators inside a quoted
+    // value are not mistaken for the comparison
+    let found = expression.char_indices().find_map(|(i, _)| {
+        OPERATORS
+            .iter()
+            .find(|(symbol, _)| expression[i..].starts_with(symbol))
+            .map(|(symbol, op)| (i, symbol.len(), *op))
+    });
+
+    let (variable, comparison) = match found {
+        Some((at, len, operator)) => {
+            let value = expression[at + len..].trim();
+            if value.is_empty() {
+                return Err(BdlError::ParseError(format!("missing value after '{}'", operator.symbol()).into()));
+            }
+            let value = BdlValue::parse(value)?;
+            (&expression[..at], Some(BdlComparison { operator, value }))
+        }
+        None => (expression, None),
+    };
+
+    let variable = variable.trim();
+    if !is_identifier(variable) {
+        return Err(BdlError::ParseError(format!("invalid condition variable '{}'", variable).into()));
+    }
+    Ok(BdlCondition { variable: variable.to_string(), comparison })
+}
+
+/// Split an all-of keyword list like `+open +door` into its words
+///
+/// Every word needs the `+` marker; commas between words are allowed.
+fn parse_required_keywords(list: &str) -> Result<Vec<String>, &'static str> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| match word.strip_prefix('+') {
+            Some(keyword) if !keyword.is_empty() && !keyword.contains('+') => Ok(keyword.to_string()),
+            Some(_) => Err("empty keyword"),
+            None => Err("cannot mix '+' keywords with plain ones"),
+        })
+        .collect()
+}
+
+/// Split an option's keyword list, expanding `word=(synonym, ...)` groups
+///
+/// `{yes=(y, yeah), no}` yields `yes`, `y`, `yeah`, `no`, in that order.
+fn parse_keywords(list: &str) -> Result<Vec<String>, &'static str> {
+    let mut keywords = Vec::new();
+    let mut rest = list;
+
+    loop {
+        let comma = rest.find(',').unwrap_or(rest.len());
+        let item = match rest[..comma].split_once('=') {
+            // Synonym groups contain commas, so read up to the closing paren
+            Some((head, _)) => {
+                let group = rest[head.len() + 1..]
+                    .trim_start()
+                    .strip_prefix('(')
+                    .ok_or("expected '(' after '='")?;
+                let (synonyms, after) = group.split_once(')').ok_or("unterminated synonym group")?;
+                keywords.push(head.trim().to_string());
+                keywords.extend(synonyms.split(',').map(|k| k.trim().to_string()));
+                let after = after.trim_start();
+                if !after.is_empty() && !after.starts_with(',') {
+                    return Err("unexpected text after synonym group");
+                }
+                rest = after;
+                None
+            }
+            None => {
+                let item = &rest[..comma];
+                rest = &rest[comma..];
+                Some(item)
+            }
+        };
+        if let Some(item) = item {
+            keywords.push(item.trim().to_string());
+        }
+
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after,
+            None => break,
+        }
+    }
+
+    if keywords.iter().any(|k| k.is_empty()) {
+        return Err("empty keyword");
+    }
+    Ok(keywords)
+}
+
+/// Split a trailing `/// doc` comment off an option line
+///
+/// A `///` inside a quoted condition value does not start a comment. An
+/// empty comment gives no doc.
+fn split_doc_comment(line: &str) -> (&str, Option<String>) {
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '/' if !in_quotes && line[index..].starts_with("///") => {
+                let doc = line[index + 3..].trim();
+                return (line[..index].trim_end(), (!doc.is_empty()).then(|| doc.to_string()));
+            }
+            _ => {}
+        }
+    }
+    (line, None)
+}
+
+/// Node names are non-empty and use only letters, digits and underscores
+fn is_valid_node_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Parse a `#!bdl <version>` shebang and check the version is supported
+fn parse_format_version(line: &str) -> Result<u32, BdlError> {
+    let version = line
+        .strip_prefix("#!bdl")
+        .and_then(|rest| rest.trim().parse::<u32>().ok())
+        .ok_or_else(|| BdlError::ParseError(format!("Invalid format declaration: {}", line).into()))?;
+
+    if !SUPPORTED_FORMAT_VERSIONS.contains(&version) {
+        return Err(BdlError::ParseError(format!("Unsupported BDL format version: {}", version).into()));
+    }
+
+    Ok(version)
+}
+
+/// Check that a version string follows semver (MAJOR.MINOR.PATCH[-pre][+build])
+fn is_valid_semver(version: &str) -> bool {
+    let is_identifier = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    let is_numeric = |s: &str| {
+        !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) && (s == "0" || !s.starts_with('0'))
+    };
+
+    let (version, build) = match version.split_once('+') {
+        Some((version, build)) => (version, Some(build)),
+        None => (version, None),
+    };
+    let (core, pre) = match version.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (version, None),
+    };
+
+    let parts: Vec<&str> = core.split('.').collect();
+    if parts.len() != 3 || !parts.iter().all(|part| is_numeric(part)) {
+        return false;
+    }
+
+    [pre, build]
+        .into_iter()
+        .flatten()
+        .all(|extra| extra.split('.').all(is_identifier))
+}
+
+/// Parse a single variable declaration line
+fn parse_variable_line(line: &str) -> Result<Option<(String, BdlValue)>, BdlError> {
+    // Skip empty lines and closing braces
+    if line.trim().is_empty() || line.trim() == "}" {
+        return Ok(None);
+    }
+
+    // Split key and value at the first colon; map values contain more
+    let Some((key, value)) = line.split_once(':') else {
+        return Err(BdlError::ParseError(format!("Invalid variable declaration: {}", line).into()));
+    };
+
+    let key = key.trim().to_string();
+    let value = value.trim().trim_end_matches(',');
+
+    Ok(Some((key, BdlValue::parse(value)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_empty_metadata() {
+        let parser = BdlParser::new("".to_string());
+        let metadata = parser.parse_metadata().unwrap();
+        assert!(metadata.topic.is_none());
+        assert!(metadata.description.is_none());
+        assert!(metadata.author.is_none());
+        assert!(metadata.version.is_none());
+        assert!(metadata.required.is_none());
+    }
+
+    #[test]
+    fn test_parse_complete_metadata() {
+        let content = "\
+# Topic: Test Dialog
+# Description: A test dialog file
+# Author: Test Author
+# Version: 1.0
+# Required: mod1.bdl, mod2.bdl
+
+@start
+Some content...";
+
+        let parser = BdlParser::new(content.to_string());
+        let metadata = parser.parse_metadata().unwrap();
+        
+        assert_eq!(metadata.topic, Some("Test Dialog".to_string()));
+        assert_eq!(metadata.description, Some("A test dialog file".to_string()));
+        assert_eq!(metadata.author, Some("Test Author".to_string()));
+        assert_eq!(metadata.version, Some("1.0".to_string()));
+        assert_eq!(metadata.required, Some(vec!["mod1.bdl".to_string(), "mod2.bdl".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_partial_metadata() {
+        let content = "\
+# Topic: Partial Test
+# Author: Test Author
+
+@start";
+
+        let parser = BdlParser::new(content.to_string());
+        let metadata = parser.parse_metadata().unwrap();
+        
+        assert_eq!(metadata.topic, Some("Partial Test".to_string()));
+        assert!(metadata.description.is_none());
+        assert_eq!(metadata.author, Some("Test Author".to_string()));
+        assert!(metadata.version.is_none());
+        assert!(metadata.required.is_none());
+    }
+
+    #[test]
+    fn test_parse_metadata_with_comments() {
+        let content = "\
+# This is a comment
+# Topic: With Comments
+# Another comment
+# Author: Test Author
+# Description: Test Description
+# Yet another comment
+
+Content starts here...";
+
+        let parser = BdlParser::new(content.to_string());
+        let metadata = parser.parse_metadata().unwrap();
+        
+        assert_eq!(metadata.topic, Some("With Comments".to_string()));
+        assert_eq!(metadata.description, Some("Test Description".to_string()));
+        assert_eq!(metadata.author, Some("Test Author".to_string()));
+        assert!(metadata.version.is_none());
+        assert!(metadata.required.is_none());
+    }
+
+    #[test]
+    fn test_parse_empty_variables() {
+        let content = "# Empty file";
+        let parser = BdlParser::new(content.to_string());
+        let (global, local) = parser.parse_variables().unwrap();
+        assert!(global.is_none());
+        assert!(local.is_empty());
     }
 
     #[test]
@@ -349,10 +1561,10 @@ $global_vars: {
         let globals = global.unwrap();
         assert_eq!(globals.len(), 5);
         assert!(matches!(globals.get("user_name"), Some(BdlValue::String(s)) if s.is_empty()));
-        assert!(matches!(globals.get("score"), Some(BdlValue::Number(n)) if *n == 0.0));
+        assert!(matches!(globals.get("score"), Some(BdlValue::Integer(0))));
         assert!(matches!(globals.get("is_complete"), Some(BdlValue::Boolean(b)) if !b));
         assert!(matches!(globals.get("high_score"), Some(BdlValue::Number(n)) if *n == 100.5));
-        assert!(matches!(globals.get("inventory"), Some(BdlValue::Empty)));
+        assert!(matches!(globals.get("inventory"), Some(BdlValue::Map(m)) if m.is_empty()));
         assert!(local.is_empty());
     }
 
@@ -371,8 +1583,8 @@ $local_vars: {
         
         assert!(global.is_none());
         assert_eq!(local.len(), 4);
-        assert!(matches!(local.get("attempts"), Some(BdlValue::Number(n)) if *n == 0.0));
-        assert!(matches!(local.get("current_progress"), Some(BdlValue::Number(n)) if *n == 50.0));
+        assert!(matches!(local.get("attempts"), Some(BdlValue::Integer(0))));
+        assert!(matches!(local.get("current_progress"), Some(BdlValue::Integer(50))));
         assert!(matches!(local.get("has_key"), Some(BdlValue::Boolean(b)) if *b));
         assert!(matches!(local.get("player_name"), Some(BdlValue::String(s)) if s == "John"));
     }
@@ -393,10 +1605,10 @@ $local_vars: {
         
         let globals = global.unwrap();
         assert_eq!(globals.len(), 1);
-        assert!(matches!(globals.get("score"), Some(BdlValue::Number(n)) if *n == 0.0));
+        assert!(matches!(globals.get("score"), Some(BdlValue::Integer(0))));
         
         assert_eq!(local.len(), 1);
-        assert!(matches!(local.get("attempts"), Some(BdlValue::Number(n)) if *n == 3.0));
+        assert!(matches!(local.get("attempts"), Some(BdlValue::Integer(3))));
     }
 
     #[test]
@@ -593,4 +1805,1173 @@ Second node content
         assert!(nodes.contains_key("node1"));
         assert!(nodes.contains_key("node2"));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_gated_node_included_when_flag_enabled() {
+        let content = r#"
+@start
+Welcome!
+
+@secret #if full
+Bonus content
+"#;
+        let mut options = ParserOptions::default();
+        options.enabled_flags.insert("full".to_string());
+        let parser = BdlParser::with_options(content.to_string(), options);
+        let deps = create_test_dependencies();
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes.get("secret").unwrap().name, "secret");
+    }
+
+    #[test]
+    fn test_gated_node_excluded_when_flag_disabled() {
+        let content = r#"
+@start
+Welcome!
+
+@secret #if full
+Bonus content
+
+@end
+Goodbye
+"#;
+        let parser = BdlParser::new(content.to_string());
+        let deps = create_test_dependencies();
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert!(!nodes.contains_key("secret"));
+
+        // Content of the gated node must not leak into its neighbours
+        let start = nodes.get("start").unwrap();
+        assert_eq!(start.content.len(), 1);
+        assert!(nodes.contains_key("end"));
+    }
+
+    #[test]
+    fn test_node_tags() {
+        let content = "@shop #merchant #town\nWelcome!\n@vault #locked #if full\n@inn";
+        let mut options = ParserOptions::default();
+        options.enabled_flags.insert("full".to_string());
+        let parser = BdlParser::with_options(content.to_string(), options);
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        assert_eq!(nodes["shop"].tags, vec!["merchant", "town"]);
+        assert_eq!(nodes["vault"].tags, vec!["locked"]);
+        assert!(nodes["inn"].tags.is_empty());
+
+        let nodes = BdlParser::new("@debug #allow-unreachable\n@main".to_string())
+            .parse_nodes(&create_test_dependencies())
+            .unwrap();
+        assert!(nodes["debug"].allows_unreachable());
+        assert!(!nodes["main"].allows_unreachable());
+
+        for header in ["@a #two words", "@a #", "@a #if x #if y"] {
+            let parser = BdlParser::new(header.to_string());
+            assert!(
+                matches!(parser.parse_nodes(&create_test_dependencies()), Err(BdlError::ParseError(_))),
+                "{:?} should be rejected",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_node_annotation() {
+        let content = "@secret #unless full";
+        let parser = BdlParser::new(content.to_string());
+        let deps = create_test_dependencies();
+
+        assert!(matches!(
+            parser.parse_nodes(&deps),
+            Err(BdlError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_indentation_trimmed_by_default() {
+        let content = "@menu\n    1. Indented item";
+        let parser = BdlParser::new(content.to_string());
+        let deps = create_test_dependencies();
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        match &nodes.get("menu").unwrap().content[0] {
+            BdlContentElement::Text(text) => assert_eq!(text, "1. Indented item"),
+            _ => panic!("Expected Text content"),
+        }
+    }
+
+    #[test]
+    fn test_indentation_preserved_when_enabled() {
+        let content = "@menu
+    1. Indented item
+    @other
+    Indented node";
+        let options = ParserOptions {
+            preserve_indentation: true,
+            ..Default::default()
+        };
+        let parser = BdlParser::with_options(content.to_string(), options);
+        let deps = create_test_dependencies();
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        match &nodes.get("menu").unwrap().content[0] {
+            BdlContentElement::Text(text) => assert_eq!(text, "    1. Indented item"),
+            _ => panic!("Expected Text content"),
+        }
+
+        // Indented markers are still recognised
+        assert!(nodes.contains_key("other"));
+    }
+
+    #[test]
+    fn test_strict_version_accepts_semver() {
+        let content = "# Version: 1.2.3";
+        let options = ParserOptions {
+            strict_version: true,
+            ..Default::default()
+        };
+        let parser = BdlParser::with_options(content.to_string(), options);
+        let metadata = parser.parse_metadata().unwrap();
+        assert_eq!(metadata.version, Some("1.2.3".to_string()));
+
+        assert!(is_valid_semver("1.0.0-alpha.1+build.5"));
+        assert!(!is_valid_semver("01.0.0"));
+    }
+
+    #[test]
+    fn test_strict_version_rejects_invalid_semver() {
+        let content = "# Version: 1.x";
+        let options = ParserOptions {
+            strict_version: true,
+            ..Default::default()
+        };
+        let parser = BdlParser::with_options(content.to_string(), options);
+        assert!(matches!(
+            parser.parse_metadata(),
+            Err(BdlError::ParseError(_))
+        ));
+
+        // Without strict mode the version is kept as-is
+        let parser = BdlParser::new(content.to_string());
+        assert_eq!(parser.parse_metadata().unwrap().version, Some("1.x".to_string()));
+    }
+
+    #[test]
+    fn test_global_vars_allowed_in_main() {
+        let content = r#"
+$global_vars: {
+    score: 0
+}
+"#;
+        let parser = BdlParser::new(content.to_string()).with_filename("modules/main.bdl");
+        let (global, _) = parser.parse_variables().unwrap();
+        assert_eq!(global.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_global_vars_rejected_outside_main() {
+        let content = r#"
+$global_vars: {
+    score: 0
+}
+"#;
+        let parser = BdlParser::new(content.to_string()).with_filename("other.bdl");
+        assert!(matches!(
+            parser.parse_variables(),
+            Err(BdlError::VariableError(_))
+        ));
+
+        // Local variables are fine in any file
+        let parser = BdlParser::new("$local_vars: {\n    tries: 0\n}".to_string()).with_filename("other.bdl");
+        assert!(parser.parse_variables().is_ok());
+    }
+
+    #[test]
+    fn test_filename_combined_with_options() {
+        let content = "# Version: 1.0\n$global_vars: {\n    score: 0\n}\n";
+        let options = ParserOptions {
+            strict_version: true,
+            ..ParserOptions::default()
+        };
+
+        // Both the options and the filename are in effect
+        let parser = BdlParser::with_options(content.to_string(), options.clone()).with_filename("main.bdl");
+        assert!(parser.parse_metadata().is_err());
+        assert!(parser.parse_variables().is_ok());
+
+        let parser = BdlParser::with_options(content.to_string(), options).with_filename("other.bdl");
+        assert!(matches!(parser.parse_variables(), Err(BdlError::VariableError(_))));
+    }
+
+    #[test]
+    fn test_line_continuation_joins_option_line() {
+        let content = "@menu\n{yes, \\\n    ok, sure} -> @accept\nNext line";
+        let parser = BdlParser::new(content.to_string());
+
+        let lines = parser.logical_lines().unwrap();
+        assert_eq!(lines, vec!["@menu", "{yes, ok, sure} -> @accept", "Next line"]);
+    }
+
+    #[test]
+    fn test_escaped_trailing_backslash() {
+        let content = "@paths\nC:\\Games\\\\\nStill separate";
+        let parser = BdlParser::new(content.to_string());
+        let deps = create_test_dependencies();
+
+        assert_eq!(parser.logical_lines().unwrap(), vec!["@paths", "C:\\Games\\", "Still separate"]);
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        let node = nodes.get("paths").unwrap();
+        assert!(matches!(&node.content[0], BdlContentElement::Text(text) if text == "C:\\Games\\\nStill separate"));
+    }
+
+    #[test]
+    fn test_parse_format_version_shebang() {
+        let content = "\
+#!bdl 1
+# Topic: Versioned
+
+@start
+Hello";
+        let parser = BdlParser::new(content.to_string());
+        let metadata = parser.parse_metadata().unwrap();
+        assert_eq!(metadata.format_version, Some(1));
+        assert_eq!(metadata.topic, Some("Versioned".to_string()));
+
+        // The shebang is neither a node nor content
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes.get("start").unwrap().content.len(), 1);
+    }
+
+    #[test]
+    fn test_unsupported_format_version() {
+        let parser = BdlParser::new("#!bdl 99\n# Topic: Future".to_string());
+        assert!(matches!(
+            parser.parse_metadata(),
+            Err(BdlError::ParseError(_))
+        ));
+
+        let parser = BdlParser::new("#!python".to_string());
+        assert!(matches!(
+            parser.parse_metadata(),
+            Err(BdlError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_comments_inside_variable_block() {
+        let content = r#"
+$local_vars: {
+    gold: 10,
+    # Costs $5 per visit to the $local_vars shop
+    visits: 0
+}
+"#;
+        let parser = BdlParser::new(content.to_string());
+        let (_, local) = parser.parse_variables().unwrap();
+
+        assert_eq!(local.len(), 2);
+        assert!(matches!(local.get("gold"), Some(BdlValue::Integer(10))));
+        assert!(matches!(local.get("visits"), Some(BdlValue::Integer(0))));
+    }
+
+    #[test]
+    fn test_unterminated_variable_block() {
+        let content = r#"
+$local_vars: {
+    attempts: 0,
+    max_attempts: 3
+"#;
+        let parser = BdlParser::new(content.to_string());
+        match parser.parse_variables() {
+            Err(BdlError::ParseError(message)) => assert!(message.contains("$local_vars")),
+            other => panic!("Expected unterminated block error, got {:?}", other),
+        }
+
+        // A block left open before the next one starts is also reported
+        let content = "$global_vars: {\n    score: 0\n$local_vars: {\n}";
+        let parser = BdlParser::new(content.to_string());
+        match parser.parse_variables() {
+            Err(BdlError::ParseError(message)) => assert!(message.contains("$global_vars")),
+            other => panic!("Expected unterminated block error, got {:?}", other),
+        }
+
+        // Empty inline blocks are closed on the same line
+        let parser = BdlParser::new("$local_vars: {}".to_string());
+        let (_, local) = parser.parse_variables().unwrap();
+        assert!(local.is_empty());
+    }
+
+    #[test]
+    fn test_max_line_length_exceeded() {
+        let content = format!("@start\nShort line\n{}", "x".repeat(100));
+        let options = ParserOptions {
+            max_line_length: Some(80),
+            ..Default::default()
+        };
+        let parser = BdlParser::with_options(content.clone(), options);
+        let deps = create_test_dependencies();
+
+        match parser.parse_nodes(&deps) {
+            Err(BdlError::ParseError(message)) => assert!(message.contains("Line 3")),
+            other => panic!("Expected line length error, got {:?}", other),
+        }
+
+        // No limit by default
+        let parser = BdlParser::new(content);
+        assert!(parser.parse_nodes(&deps).is_ok());
+    }
+
+    #[test]
+    fn test_parse_changelog_metadata() {
+        let content = "\
+# Topic: Changelog Test
+# Changelog:
+# - 1.0: Initial release
+# - 1.1: Added password quiz
+# - 1.2: Fixed typos
+# Author: Test Author
+
+@start";
+
+        let parser = BdlParser::new(content.to_string());
+        let metadata = parser.parse_metadata().unwrap();
+
+        assert_eq!(metadata.changelog, Some(vec![
+            "1.0: Initial release".to_string(),
+            "1.1: Added password quiz".to_string(),
+            "1.2: Fixed typos".to_string(),
+        ]));
+        assert_eq!(metadata.author, Some("Test Author".to_string()));
+
+        // Without a Changelog header, list-like comments are ignored
+        let parser = BdlParser::new("# Topic: None\n# - stray".to_string());
+        assert!(parser.parse_metadata().unwrap().changelog.is_none());
+    }
+
+    #[test]
+    fn test_custom_directive() {
+        let content = "@scene\nThe door creaks.\n%playsound creak.ogg\nSomeone is there.";
+        let mut parser = BdlParser::new(content.to_string());
+        parser.register_directive("playsound", |args| Ok(args.to_uppercase()));
+        let deps = create_test_dependencies();
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        let node = nodes.get("scene").unwrap();
+        assert_eq!(node.content.len(), 3);
+        assert!(matches!(
+            &node.content[1],
+            BdlContentElement::Custom { tag, data } if tag == "playsound" && data == "CREAK.OGG"
+        ));
+    }
+
+    #[test]
+    fn test_unregistered_directive_policy() {
+        let content = "@scene\n%shake 3";
+        let deps = create_test_dependencies();
+
+        // Passed through as text by default
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        assert!(matches!(
+            &nodes.get("scene").unwrap().content[0],
+            BdlContentElement::Text(text) if text == "%shake 3"
+        ));
+
+        let options = ParserOptions {
+            unknown_directive_policy: UnknownDirectivePolicy::Error,
+            ..Default::default()
+        };
+        let parser = BdlParser::with_options(content.to_string(), options);
+        assert!(matches!(
+            parser.parse_nodes(&deps),
+            Err(BdlError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_data_block_preserved() {
+        let content = r#"@shop
+Welcome to the shop.
+%data application/json {
+  "items": [{"name": "rope}", "price": 5}],
+  "open": true
+}"#;
+        let parser = BdlParser::new(content.to_string());
+        let deps = create_test_dependencies();
+
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        let node = nodes.get("shop").unwrap();
+        assert_eq!(node.content.len(), 2);
+        match &node.content[1] {
+            BdlContentElement::Data { mime, body } => {
+                assert_eq!(mime, "application/json");
+                assert_eq!(body, "\n  \"items\": [{\"name\": \"rope}\", \"price\": 5}],\n  \"open\": true\n");
+                assert!(serde_json::from_str::<serde_json::Value>(&format!("{{{}}}", body)).is_ok());
+            }
+            other => panic!("Expected Data content, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unterminated_data_block() {
+        let content = "@shop\n%data application/json {\n  \"a\": {\"b\": 1}\n@next";
+        let parser = BdlParser::new(content.to_string());
+        let deps = create_test_dependencies();
+
+        assert!(matches!(
+            parser.parse_nodes(&deps),
+            Err(BdlError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_variable_in_block() {
+        let content = r#"
+$local_vars: {
+    score: 0,
+    lives: 3,
+    score: 10
+}
+"#;
+        let parser = BdlParser::new(content.to_string());
+        match parser.parse_variables() {
+            Err(BdlError::VariableError(message)) => assert!(message.contains("'score'")),
+            other => panic!("Expected duplicate variable error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parser_from_lines() {
+        let lines = vec![
+            "# Topic: Streamed".to_string(),
+            "@first".to_string(),
+            "Line one".to_string(),
+            "@second".to_string(),
+            "Line two".to_string(),
+        ];
+        let parser = BdlParser::from_lines(lines.into_iter());
+        let deps = create_test_dependencies();
+
+        assert_eq!(parser.parse_metadata().unwrap().topic, Some("Streamed".to_string()));
+        let nodes = parser.parse_nodes(&deps).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert!(matches!(
+            &nodes.get("second").unwrap().content[0],
+            BdlContentElement::Text(text) if text == "Line two"
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_required_dependency() {
+        let content = "# Required: mod1.bdl, mod2.bdl, mod1.bdl\n";
+        let parser = BdlParser::new(content.to_string());
+        assert!(matches!(parser.parse_metadata(), Err(BdlError::DependencyError(_))));
+
+    }
+
+    #[test]
+    fn test_parse_option_destinations() {
+        let parser = BdlParser::new(String::new());
+        let deps: HashSet<String> = ["module1.bdl".to_string()].into_iter().collect();
+
+        let option = parser.parse_option("{next, continue} -> @node2", &deps).unwrap();
+        assert_eq!(option.keywords, vec!["next", "continue"]);
+        assert!(matches!(option.destination, BdlDestination::Node(ref n) if n == "node2"));
+        assert!(option.condition.is_none());
+
+        let option = parser.parse_option("{ask} -> !{pickNext}", &deps).unwrap();
+        assert!(matches!(option.destination, BdlDestination::Computed { ref function } if function == "pickNext"));
+        assert!(matches!(parser.parse_option("{ask} -> !{pick next}", &deps), Err(BdlError::ParseError(_))));
+        assert!(matches!(parser.parse_option("{ask} -> !{pickNext", &deps), Err(BdlError::ParseError(_))));
+
+        for line in ["{again} -> @self", "{again} -> @."] {
+            let option = parser.parse_option(line, &deps).unwrap();
+            assert_eq!(option.destination, BdlDestination::Current);
+        }
+
+        let option = parser.parse_option("{help} -> help_menu", &deps).unwrap();
+        assert!(matches!(option.destination, BdlDestination::Node(ref n) if n == "help_menu"));
+
+        let option = parser.parse_option("{learn} -> [module1.bdl:start]", &deps).unwrap();
+        assert!(matches!(
+            option.destination,
+            BdlDestination::FileTransfer { ref file, ref node } if file == "module1.bdl" && node == "start"
+        ));
+
+        let option = parser.parse_option("?{has_key} {open, unlock} -> exit", &deps).unwrap();
+        assert_eq!(option.keywords, vec!["open", "unlock"]);
+        assert!(matches!(option.destination, BdlDestination::Exit));
+        assert_eq!(option.condition.unwrap().variable, "has_key");
+
+        let option = parser.parse_option("?{logged_in} -> @dashboard", &deps).unwrap();
+        assert!(option.keywords.is_empty());
+        assert_eq!(option.condition.unwrap().variable, "logged_in");
+
+        let option = parser.parse_option("{exit}", &deps).unwrap();
+        assert!(matches!(option.destination, BdlDestination::Exit));
+    }
+
+    #[test]
+    fn test_parse_option_errors() {
+        let parser = BdlParser::new(String::new());
+        let deps: HashSet<String> = ["module1.bdl".to_string()].into_iter().collect();
+
+        for line in [
+            "{next -> @node2",
+            "{next} @node2",
+            "{next, } -> @node2",
+            "{next} -> ",
+            "{next} -> @bad name",
+            "?{} {next} -> @node2",
+            "?{flag -> @node2",
+            "{next} -> [module1.bdl]",
+        ] {
+            assert!(
+                matches!(parser.parse_option(line, &deps), Err(BdlError::ParseError(_))),
+                "line {:?} should be rejected",
+                line
+            );
+        }
+
+        // Transfers to files that were not declared are a dependency problem
+        assert!(matches!(
+            parser.parse_option("{go} -> [other.bdl:start]", &deps),
+            Err(BdlError::DependencyError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_option_doc_comment() {
+        let parser = BdlParser::new(String::new());
+        let deps = create_test_dependencies();
+
+        let option = parser.parse_option("{yes} -> @accept  /// the agreeable choice", &deps).unwrap();
+        assert_eq!(option.doc.as_deref(), Some("the agreeable choice"));
+        assert!(matches!(option.destination, BdlDestination::Node(ref n) if n == "accept"));
+        let json = serde_json::to_string(&option).unwrap();
+        let back: BdlBranchOption = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.doc.as_deref(), Some("the agreeable choice"));
+
+        let option = parser.parse_option("{yes} -> @accept", &deps).unwrap();
+        assert!(option.doc.is_none());
+
+        let option = parser.parse_option("{exit} /// leave", &deps).unwrap();
+        assert_eq!(option.doc.as_deref(), Some("leave"));
+
+        // Inside a quoted value `///` is just text
+        let option = parser.parse_option(r#"?{path == "a///b"} {go} -> @accept"#, &deps).unwrap();
+        assert!(option.doc.is_none());
+        assert_eq!(option.condition.unwrap().to_string(), r#"path == "a///b""#);
+    }
+
+    #[test]
+    fn test_options_in_display_order() {
+        let content = r#"@menu
+{quit} -> exit
+{yes}@2 -> @accept
+{no}@1 -> @decline
+{+ask +again}@-1 -> @menu
+{maybe}@1-> @decline
+"#;
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+        let menu = &nodes["menu"];
+
+        let orders: Vec<Option<i32>> = menu.options.iter().map(|o| o.order).collect();
+        assert_eq!(orders, vec![None, Some(2), Some(1), Some(-1), Some(1)]);
+
+        // Ties keep source order and unannotated options come last
+        let shown: Vec<&str> = menu.options_in_display_order().iter().map(|o| o.keywords[0].as_str()).collect();
+        assert_eq!(shown, vec!["ask", "no", "maybe", "yes", "quit"]);
+
+        assert!(matches!(
+            parser.parse_option("{yes}@first -> @accept", &create_test_dependencies()),
+            Err(BdlError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_option_empty_keywords() {
+        let parser = BdlParser::new(String::new());
+        let deps = create_test_dependencies();
+
+        for line in ["{,yes} -> @accept", "{yes,,no} -> @accept", "{yes, } -> @accept", "{} -> @accept"] {
+            assert!(
+                matches!(parser.parse_option(line, &deps), Err(BdlError::ParseError(msg)) if msg.contains("empty keyword")),
+                "line {:?} should be rejected",
+                line
+            );
+        }
+
+        // Leaving out the keyword list is how a keywordless default is written
+        let option = parser.parse_option("?{ready} -> @accept", &deps).unwrap();
+        assert!(option.keywords.is_empty());
+        assert!(matches!(option.destination, BdlDestination::Node(ref n) if n == "accept"));
+
+        let option = parser.parse_option("{exit}", &deps).unwrap();
+        assert!(option.keywords.is_empty());
+        assert!(matches!(option.destination, BdlDestination::Exit));
+    }
+
+    #[test]
+    fn test_parse_variable_interpolation() {
+        let content = "@greet\nHello ${name}, welcome to ${place}!\n@pair\n${a}${b}";
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        let greet: Vec<String> = nodes["greet"].content.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            greet,
+            vec![
+                r#"Text("Hello ")"#,
+                r#"Variable("name")"#,
+                r#"Text(", welcome to ")"#,
+                r#"Variable("place")"#,
+                r#"Text("!")"#,
+            ]
+        );
+
+        let pair: Vec<String> = nodes["pair"].content.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(pair, vec![r#"Variable("a")"#, r#"Variable("b")"#]);
+    }
+
+    #[test]
+    fn test_unterminated_variable_interpolation() {
+        let parser = BdlParser::new("@greet\nHello ${name, welcome!".to_string());
+        match parser.parse_nodes(&create_test_dependencies()) {
+            Err(BdlError::ParseError(msg)) => assert!(msg.contains("Hello ${name, welcome!")),
+            other => panic!("Expected ParseError, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_function_calls() {
+        let content = "@roll\nRolling... !{roll -> result}! You got ${result}.\n\
+                       !{fetch -> a, b}${a}\n\
+                       !{getCurrentTime} : ~{time} ~{next}\n\
+                       !{ping}";
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        let found: Vec<String> = nodes["roll"].content.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            found,
+            vec![
+                r#"Text("Rolling... ")"#,
+                r#"FunctionCall { name: "roll", args: [], result_vars: ["result"] }"#,
+                r#"Text("! You got ")"#,
+                r#"Variable("result")"#,
+                r#"Text(".\n")"#,
+                r#"FunctionCall { name: "fetch", args: [], result_vars: ["a", "b"] }"#,
+                r#"Variable("a")"#,
+                r#"Text("\n")"#,
+                r#"FunctionCall { name: "getCurrentTime", args: [], result_vars: ["time", "next"] }"#,
+                r#"Text("\n")"#,
+                r#"FunctionCall { name: "ping", args: [], result_vars: [] }"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_function_calls() {
+        for line in ["!{}", "!{ -> a}", "!{2fast}", "!{fetch -> a, }", "!{fetch"] {
+            let parser = BdlParser::new(format!("@node\n{}", line));
+            assert!(
+                matches!(parser.parse_nodes(&create_test_dependencies()), Err(BdlError::ParseError(_))),
+                "line {:?} should be rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_full_document() {
+        let content = r#"# Topic: Full
+# Author: Tester
+# Required: module1.bdl
+
+$global_vars: {
+    score: 0
+}
+
+$local_vars: {
+    name: "guest"
+}
+
+@start
+Hello ${name}!
+{go, next} -> @finish
+{lesson} -> [module1.bdl:start]
+
+@finish
+Your score is ${score}.
+{exit}
+"#;
+        let doc = BdlParser::new(content.to_string()).parse().unwrap();
+
+        assert_eq!(doc.metadata.topic, Some("Full".to_string()));
+        assert_eq!(doc.metadata.required, Some(vec!["module1.bdl".to_string()]));
+        assert!(matches!(
+            doc.global_vars.as_ref().unwrap().get("score"),
+            Some(BdlValue::Integer(0))
+        ));
+        assert!(matches!(doc.local_vars.get("name"), Some(BdlValue::String(s)) if s == "guest"));
+
+        assert_eq!(doc.nodes.len(), 2);
+        let start = &doc.nodes["start"];
+        assert_eq!(start.options.len(), 2);
+        assert!(matches!(&start.options[0].destination, BdlDestination::Node(n) if n == "finish"));
+        assert!(matches!(
+            &start.options[1].destination,
+            BdlDestination::FileTransfer { file, .. } if file == "module1.bdl"
+        ));
+        assert!(matches!(doc.nodes["finish"].options[0].destination, BdlDestination::Exit));
+    }
+
+    #[test]
+    fn test_parse_surfaces_undeclared_dependency() {
+        let content = "@start\n{go} -> [module1.bdl:start]\n";
+        let result = BdlParser::new(content.to_string()).parse();
+        assert!(matches!(result, Err(BdlError::DependencyError(_))));
+    }
+
+    #[test]
+    fn test_error_reports_line_number() {
+        let content = "# Topic: Broken\n\n$local_vars: {\n    name: \"ok\",\n    score: foo\n}\n";
+        let parser = BdlParser::new(content.to_string());
+        let error = parser.parse_variables().unwrap_err();
+
+        assert_eq!(error.span(), Some(Span { line: 5, column: 5 }));
+        assert_eq!(error.to_string(), "Parse error at line 5: Invalid value format: foo");
+
+        // Continued lines report the line they start on
+        let content = "@start\nHello \\\n    ${name\nBye";
+        let error = BdlParser::new(content.to_string()).parse_nodes(&HashSet::new()).unwrap_err();
+        assert_eq!(error.span().map(|s| s.line), Some(2));
+
+        // Errors without a position keep the plain form
+        let error = BdlError::NodeError("Node 'x' already exists".into());
+        assert_eq!(error.to_string(), "Node error: Node 'x' already exists");
+    }
+
+    #[test]
+    fn test_parse_option_synonyms() {
+        let parser = BdlParser::new(String::new());
+        let deps = create_test_dependencies();
+
+        let option = parser.parse_option("{yes=(y, yeah, sure), ok} -> @accept", &deps).unwrap();
+        assert_eq!(option.keywords, vec!["yes", "y", "yeah", "sure", "ok"]);
+        assert!(matches!(option.destination, BdlDestination::Node(ref n) if n == "accept"));
+
+        for input in ["yes", "y", "yeah", "sure", "ok"] {
+            assert!(option.keywords.iter().any(|k| k == input), "{:?} should trigger the option", input);
+        }
+
+        for line in ["{yes=(y, ) } -> @accept", "{yes=y} -> @accept", "{yes=(y, yeah} -> @accept", "{yes=(y) no} -> @accept", "{yes=y, no=(n)} -> @accept"] {
+            assert!(
+                matches!(parser.parse_option(line, &deps), Err(BdlError::ParseError(_))),
+                "line {:?} should be rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_auto_named_anonymous_nodes() {
+        let content = "---\nIt was a dark night.\n---\nA door creaked.\n{run} -> @escape\n@escape\nYou ran.";
+        let options = ParserOptions {
+            auto_name_anonymous: true,
+            ..Default::default()
+        };
+        let parser = BdlParser::with_options(content.to_string(), options);
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        assert_eq!(nodes.len(), 3);
+        let first = &nodes["__auto_0"];
+        assert_eq!(first.options.len(), 1);
+        assert!(first.options[0].keywords.is_empty());
+        assert!(matches!(&first.options[0].destination, BdlDestination::Node(n) if n == "__auto_1"));
+
+        // A node with its own options does not fall through
+        let second = &nodes["__auto_1"];
+        assert_eq!(second.options.len(), 1);
+        assert_eq!(second.options[0].keywords, vec!["run"]);
+
+        // Without the option the marker is ordinary text
+        let parser = BdlParser::new("@start\n---".to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+        assert_eq!(nodes.len(), 1);
+
+        // A marker whose generated name is already taken is skipped with its content
+        let content = "@__auto_1\nNamed.\n---\nFirst.\n---\nClashes.\n";
+        let options = ParserOptions {
+            auto_name_anonymous: true,
+            ..Default::default()
+        };
+        let (doc, errors) = BdlParser::with_options(content.to_string(), options).parse_all();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], BdlError::NodeError(msg) if msg.contains("__auto_1")));
+        assert_eq!(errors[0].span().map(|span| span.line), Some(5));
+        assert!(doc.nodes["__auto_0"].options.is_empty());
+        assert_eq!(format!("{:?}", doc.nodes["__auto_1"].content), r#"[Text("Named.")]"#);
+    }
+
+    #[test]
+    fn test_escaped_interpolation_markers() {
+        let content = "@price\nThat costs \\${price}, not ${cost}\\!\n@shout\nLiteral \\!{call}";
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        let price: Vec<String> = nodes["price"].content.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            price,
+            vec![r#"Text("That costs ${price}, not ")"#, r#"Variable("cost")"#, r#"Text("!")"#]
+        );
+        assert!(matches!(
+            &nodes["shout"].content[..],
+            [BdlContentElement::Text(text)] if text == "Literal !{call}"
+        ));
+    }
+
+    #[test]
+    fn test_variable_format_specs() {
+        let content = "@stats\nScore ${score:02}, price ${ price : .2 }, ${ratio:08.3}";
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        let specs: Vec<(&str, FormatSpec)> = nodes["stats"]
+            .content
+            .iter()
+            .filter_map(|e| match e {
+                BdlContentElement::FormattedVariable { name, spec } => Some((name.as_str(), *spec)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            specs,
+            vec![
+                ("score", FormatSpec { zero_pad: true, width: Some(2), precision: None }),
+                ("price", FormatSpec { zero_pad: false, width: None, precision: Some(2) }),
+                ("ratio", FormatSpec { zero_pad: true, width: Some(8), precision: Some(3) }),
+            ]
+        );
+
+        for spec in ["", "x", "2.", ".x", "-3", "2.2.2"] {
+            let parser = BdlParser::new(format!("@a\n${{n:{}}}", spec));
+            assert!(
+                matches!(parser.parse_nodes(&create_test_dependencies()), Err(BdlError::ParseError(msg)) if msg.contains("Invalid format spec")),
+                "spec {:?} should be rejected",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_inline_conditionals() {
+        let content = r#"@basket
+${count == 1 ? "apple" : "apples"} ${mood == "?" ? "a: b" : ""}"#;
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        let parsed: Vec<String> = nodes["basket"].content.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                r#"Conditional { condition: BdlCondition { variable: "count", comparison: Some(BdlComparison { operator: Eq, value: Integer(1) }) }, then: "apple", otherwise: "apples" }"#,
+                r#"Text(" ")"#,
+                r#"Conditional { condition: BdlCondition { variable: "mood", comparison: Some(BdlComparison { operator: Eq, value: String("?") }) }, then: "a: b", otherwise: "" }"#,
+            ]
+        );
+
+        for bad in [
+            r#"${count == 1 ? "apple"}"#,
+            r#"${count == 1 ? "a" : "b" : "c"}"#,
+            r#"${count == 1 ? apple : "apples"}"#,
+            r#"${ ? "a" : "b"}"#,
+            r#"${count == ? "a" : "b"}"#,
+            r#"${count ? "a" ? "b" : "c"}"#,
+        ] {
+            let parser = BdlParser::new(format!("@a\n{}", bad));
+            assert!(
+                matches!(parser.parse_nodes(&create_test_dependencies()), Err(BdlError::ParseError(msg)) if msg.contains("Invalid inline conditional")),
+                "{} should be rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_option_comparisons() {
+        let parser = BdlParser::new(String::new());
+        let deps = create_test_dependencies();
+
+        let option = parser.parse_option("?{score >= 100} {claim} -> @prize", &deps).unwrap();
+        let condition = option.condition.unwrap();
+        assert_eq!(condition.variable, "score");
+        let comparison = condition.comparison.unwrap();
+        assert_eq!(comparison.operator, BdlCompareOp::Ge);
+        assert!(matches!(comparison.value, BdlValue::Integer(100)));
+
+        let option = parser.parse_option(r#"?{name == "a<b"} -> @greet"#, &deps).unwrap();
+        let comparison = option.condition.unwrap().comparison.unwrap();
+        assert_eq!(comparison.operator, BdlCompareOp::Eq);
+        assert!(matches!(comparison.value, BdlValue::String(ref s) if s == "a<b"));
+
+        for line in ["?{score >} -> @prize", "?{> 3} -> @prize", "?{name == Bob} -> @greet", "?{a b} -> @x"] {
+            assert!(
+                matches!(parser.parse_option(line, &deps), Err(BdlError::ParseError(_))),
+                "line {:?} should be rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_collection_variables() {
+        let content = "$local_vars: {\n    loot: [{name: \"gem\", value: 5}, {name: \"map\"}],\n    flags: []\n}\n";
+        let parser = BdlParser::new(content.to_string());
+        let (_, local) = parser.parse_variables().unwrap();
+
+        let Some(BdlValue::List(loot)) = local.get("loot") else {
+            panic!("Expected a list, got {:?}", local.get("loot"));
+        };
+        assert_eq!(loot.len(), 2);
+        assert!(matches!(&loot[0], BdlValue::Map(m) if m.get("value") == Some(&BdlValue::Integer(5))));
+        assert!(matches!(local.get("flags"), Some(BdlValue::List(l)) if l.is_empty()));
+    }
+
+    #[test]
+    fn test_paragraph_is_one_text_element() {
+        let content = "@story\nThe night was cold.\nThe wind howled.\nSomeone knocked.\n{open} -> @door\n@door\nHi ${name},\nwelcome.";
+        let parser = BdlParser::new(content.to_string());
+        let nodes = parser.parse_nodes(&create_test_dependencies()).unwrap();
+
+        let story = &nodes["story"];
+        assert_eq!(story.content.len(), 1);
+        assert!(matches!(
+            &story.content[0],
+            BdlContentElement::Text(text) if text == "The night was cold.\nThe wind howled.\nSomeone knocked."
+        ));
+        assert_eq!(story.options.len(), 1);
+
+        let door: Vec<String> = nodes["door"].content.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(door, vec![r#"Text("Hi ")"#, r#"Variable("name")"#, r#"Text(",\nwelcome.")"#]);
+    }
+
+    #[test]
+    fn test_parse_all_collects_errors() {
+        let input = r#"$local_vars: {
+    score: 0
+    not a variable
+}
+
+@start
+Hello there
+{go} -> @end
+{broken} ->
+{stay} -> @start
+
+@end
+Goodbye
+
+@start
+Shadowed greeting
+{exit}
+"#;
+        let parser = BdlParser::new(input.to_string());
+
+        let (doc, errors) = parser.parse_all();
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.span().map(|span| span.line)).collect();
+        assert_eq!(lines, vec![Some(3), Some(9), Some(15)]);
+        assert!(matches!(&errors[0], BdlError::ParseError(msg) if msg.contains("not a variable")));
+        assert!(matches!(&errors[1], BdlError::ParseError(msg) if msg.contains("{broken}")));
+        assert!(matches!(&errors[2], BdlError::NodeError(msg) if msg.contains("Duplicate node name: start")));
+
+        // Everything that parsed cleanly is kept; the duplicate node is dropped
+        assert_eq!(doc.local_vars.get("score"), Some(&BdlValue::Integer(0)));
+        assert_eq!(doc.nodes.len(), 2);
+        let start = &doc.nodes["start"];
+        assert_eq!(format!("{:?}", start.content), r#"[Text("Hello there")]"#);
+        let keywords: Vec<&str> = start.options.iter().map(|o| o.keywords[0].as_str()).collect();
+        assert_eq!(keywords, vec!["go", "stay"]);
+
+        // The strict parser still stops at the first of them
+        let error = parser.parse().unwrap_err();
+        assert_eq!(error.span().map(|span| span.line), Some(3));
+    }
+
+    #[test]
+    fn test_parse_all_keeps_metadata_with_duplicate_dependency() {
+        let input = r#"# Topic: Harbor
+# Author: Ada
+# Required: docks.bdl, docks.bdl
+
+@start
+{sail} -> [docks.bdl:pier]
+"#;
+        let parser = BdlParser::new(input.to_string());
+
+        let (doc, errors) = parser.parse_all();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], BdlError::DependencyError(msg) if msg.contains("Duplicate dependency: docks.bdl")));
+
+        // The rest of the metadata survives and the transfer is still declared
+        assert_eq!(doc.metadata.topic, Some("Harbor".to_string()));
+        assert_eq!(doc.metadata.author, Some("Ada".to_string()));
+        assert!(matches!(
+            &doc.nodes["start"].options[0].destination,
+            BdlDestination::FileTransfer { file, .. } if file == "docks.bdl"
+        ));
+
+        assert!(matches!(parser.parse(), Err(BdlError::DependencyError(_))));
+    }
+
+    #[test]
+    fn test_parse_all_recovers_node_stage_errors() {
+        let input = r#"@start
+Hello
+%shake hard
+{go} -> @cellar
+{back} -> @start
+
+@broken #no-such-tag
+Lost with its node
+
+@cellar #if lit
+Dark
+
+@end
+%data text/plain {
+Goodbye
+{exit}
+"#;
+        let options = ParserOptions {
+            unknown_directive_policy: UnknownDirectivePolicy::Error,
+            gated_node_policy: GatedNodePolicy::Error,
+            ..ParserOptions::default()
+        };
+        let parser = BdlParser::with_options(input.to_string(), options);
+
+        let (doc, errors) = parser.parse_all();
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.span().map(|span| span.line)).collect();
+        assert_eq!(lines, vec![Some(3), Some(7), Some(14), None]);
+        assert!(matches!(&errors[0], BdlError::ParseError(msg) if msg.contains("Unknown directive: %shake")));
+        assert!(matches!(&errors[1], BdlError::ParseError(msg) if msg.contains("#no-such-tag")));
+        assert!(matches!(&errors[2], BdlError::ParseError(msg) if msg.contains("Unterminated %data")));
+        assert!(matches!(&errors[3], BdlError::NodeError(msg) if msg.contains("'start'")));
+
+        // Nodes built before and after the errors are kept
+        let mut names: Vec<&str> = doc.nodes.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["end", "start"]);
+        assert_eq!(format!("{:?}", doc.nodes["start"].content), r#"[Text("Hello")]"#);
+        assert_eq!(doc.nodes["start"].options.len(), 2);
+
+        let error = parser.parse().unwrap_err();
+        assert_eq!(error.span().map(|span| span.line), Some(3));
+    }
+
+    #[test]
+    fn test_parse_function_arguments() {
+        let elements = tokenize_text(r#"!{add(score, 10) -> total} !{greet("Ada, \"the\" first", ${title}, true, -2.5)} !{now()}"#).unwrap();
+        let found: Vec<String> = elements.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            found,
+            vec![
+                r#"FunctionCall { name: "add", args: [Variable("score"), Value(Integer(10))], result_vars: ["total"] }"#,
+                r#"Text(" ")"#,
+                r#"FunctionCall { name: "greet", args: [Value(String("Ada, \"the\" first")), Variable("title"), Value(Boolean(true)), Value(Number(-2.5))], result_vars: [] }"#,
+                r#"Text(" ")"#,
+                r#"FunctionCall { name: "now", args: [], result_vars: [] }"#,
+            ]
+        );
+
+        for line in ["!{add(1,) -> x}", "!{add(1 -> x}", "!{add(1) x}", "!{add(${1x})}", "!{add(@) -> x}"] {
+            assert!(
+                matches!(tokenize_text(line), Err(BdlError::ParseError(_))),
+                "{:?} should be rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_condition_with_non_ascii_variable() {
+        let condition = parse_condition("café").unwrap();
+        assert_eq!(condition.variable, "café");
+        assert!(condition.comparison.is_none());
+
+        let condition = parse_condition("ça > 1").unwrap();
+        assert_eq!(condition.variable, "ça");
+        assert_eq!(condition.comparison.map(|c| c.operator), Some(BdlCompareOp::Gt));
+
+        let parser = BdlParser::new("@start\n?{café} {a} -> end\n".to_string());
+        let nodes = parser.parse_nodes(&HashSet::new()).unwrap();
+        assert_eq!(nodes["start"].options[0].condition.as_ref().unwrap().variable, "café");
+    }
+
+    #[test]
+    fn test_escaped_backslash_before_marker() {
+        let elements = tokenize_text(r"C:\\${dir} costs \\\${fee} \d").unwrap();
+        let found: Vec<String> = elements.iter().map(|e| format!("{:?}", e)).collect();
+        assert_eq!(
+            found,
+            vec![r#"Text("C:\\")"#, r#"Variable("dir")"#, r#"Text(" costs \\${fee} \\d")"#]
+        );
+    }
+
+    #[test]
+    fn test_trailing_option_condition() {
+        let parser = BdlParser::new(String::new());
+        let deps = create_test_dependencies();
+
+        let prefix = parser.parse_option("?{has_rope} {jump} -> @ledge", &deps).unwrap();
+        let suffix = parser.parse_option("{jump} -> @ledge ?{has_rope}", &deps).unwrap();
+        for option in [&prefix, &suffix] {
+            assert_eq!(option.keywords, vec!["jump"]);
+            assert!(matches!(&option.destination, BdlDestination::Node(n) if n == "ledge"));
+            assert_eq!(option.condition.as_ref().unwrap().variable, "has_rope");
+        }
+
+        let compared = parser.parse_option("{pay} -> [module1.bdl:shop] ?{gold >= 10}", &deps).unwrap();
+        assert!(matches!(&compared.destination, BdlDestination::FileTransfer { node, .. } if node == "shop"));
+        assert_eq!(compared.condition.unwrap().to_string(), "gold >= 10");
+
+        for line in ["?{a} {jump} -> @ledge ?{b}", "{jump} -> @ledge ?{has_rope", "{jump} -> @ledge ?{}"] {
+            assert!(
+                matches!(parser.parse_option(line, &deps), Err(BdlError::ParseError(_))),
+                "{:?} should be rejected",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_of_keywords() {
+        let parser = BdlParser::new(String::new());
+        let deps = create_test_dependencies();
+
+        let option = parser.parse_option("{+open +door} -> @doorway", &deps).unwrap();
+        assert!(option.match_all);
+        assert_eq!(option.keywords, vec!["open", "door"]);
+
+        let option = parser.parse_option("?{has_key} {+open, +door} -> @doorway", &deps).unwrap();
+        assert!(option.match_all);
+        assert_eq!(option.keywords, vec!["open", "door"]);
+
+        assert!(!parser.parse_option("{open, door} -> @doorway", &deps).unwrap().match_all);
+
+        for line in ["{+open door} -> @doorway", "{+open +} -> @doorway", "{+open+door} -> @doorway"] {
+            assert!(
+                matches!(parser.parse_option(line, &deps), Err(BdlError::ParseError(_))),
+                "{:?} should be rejected",
+                line
+            );
+        }
+    }
+}
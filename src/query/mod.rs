@@ -0,0 +1,225 @@
+use crate::{BdlBranchOption, BdlDestination, BdlDocument, BdlError, BdlNode};
+
+/// An element selected by `BdlDocument::query`
+#[derive(Debug, Clone)]
+pub enum QueryMatch<'a> {
+    /// A whole node
+    Node(&'a BdlNode),
+    /// An option, with its owning node and index within that node
+    Option {
+        node: &'a BdlNode,
+        index: usize,
+        option: &'a BdlBranchOption,
+    },
+}
+
+/// A single `[attr=value]` filter
+#[derive(Debug, PartialEq, Eq)]
+struct Filter<'s> {
+    attr: &'s str,
+    value: &'s str,
+}
+
+impl BdlDocument {
+    /// Select nodes or options with a small CSS-like selector
+    ///
+    /// A selector is `node[...]`, `option[...]`, or `node[...] option[...]`,
+    /// each with any number of `[attr=value]` filters:
+    /// - `node[name=...]` matches a node name
+    /// - `node[tag=...]` matches any of a node's `#tag` annotations
+    /// - `option[keyword=...]` matches any of an option's keywords
+    /// - `option[dest=node|file|exit|self|computed]` matches the destination type
+    ///
+    /// Results are ordered by node name, then option position.
+    pub fn query(&self, selector: &str) -> Result<Vec<QueryMatch<'_>>, BdlError> {
+        let steps: Vec<&str> = selector.split_whitespace().collect();
+        let (node_filters, option_filters) = match steps.as_slice() {
+            [single] => match parse_step(single)? {
+                ("node", filters) => (filters, None),
+                (_, filters) => (Vec::new(), Some(filters)),
+            },
+            [node, option] => match (parse_step(node)?, parse_step(option)?) {
+                (("node", node_filters), ("option", option_filters)) => (node_filters, Some(option_filters)),
+                _ => return Err(invalid_selector(selector, "expected `node[...] option[...]`")),
+            },
+            _ => return Err(invalid_selector(selector, "expected one or two steps")),
+        };
+
+        for filter in &node_filters {
+            if !matches!(filter.attr, "name" | "tag") {
+                return Err(invalid_selector(selector, &format!("unknown node attribute `{}`", filter.attr)));
+            }
+        }
+        for filter in option_filters.iter().flatten() {
+            if !matches!(filter.attr, "keyword" | "dest") {
+                return Err(invalid_selector(selector, &format!("unknown option attribute `{}`", filter.attr)));
+            }
+        }
+
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut matches = Vec::new();
+        for name in names {
+            let node = &self.nodes[name];
+            if !node_filters.iter().all(|f| node_matches(node, f)) {
+                continue;
+            }
+
+            let Some(option_filters) = &option_filters else {
+                matches.push(QueryMatch::Node(node));
+                continue;
+            };
+
+            for (index, option) in node.options.iter().enumerate() {
+                if option_filters.iter().all(|f| option_matches(option, f)) {
+                    matches.push(QueryMatch::Option { node, index, option });
+                }
+            }
+        }
+
+        Ok(matches)
+    }
+}
+
+/// Split a step like `option[keyword=buy][dest=node]` into its kind and filters
+fn parse_step(step: &str) -> Result<(&str, Vec<Filter<'_>>), BdlError> {
+    let (kind, mut rest) = step.split_at(step.find('[').unwrap_or(step.len()));
+    if kind != "node" && kind != "option" {
+        return Err(invalid_selector(step, &format!("unknown element `{}`", kind)));
+    }
+
+    let mut filters = Vec::new();
+    while !rest.is_empty() {
+        let end = rest
+            .find(']')
+            .filter(|_| rest.starts_with('['))
+            .ok_or_else(|| invalid_selector(step, "malformed filter"))?;
+        let (attr, value) = rest[1..end]
+            .split_once('=')
+            .ok_or_else(|| invalid_selector(step, "filter must be `[attr=value]`"))?;
+        filters.push(Filter { attr, value });
+        rest = &rest[end + 1..];
+    }
+
+    Ok((kind, filters))
+}
+
+fn node_matches(node: &BdlNode, filter: &Filter) -> bool {
+    match filter.attr {
+        "name" => node.name == filter.value,
+        "tag" => node.tags.iter().any(|t| t == filter.value),
+        _ => false,
+    }
+}
+
+fn option_matches(option: &BdlBranchOption, filter: &Filter) -> bool {
+    match filter.attr {
+        "keyword" => option.keywords.iter().any(|k| k == filter.value),
+        "dest" => matches!(
+            (&option.destination, filter.value),
+            (BdlDestination::Node(_), "node")
+                | (BdlDestination::FileTransfer { .. }, "file")
+                | (BdlDestination::Exit, "exit")
+                | (BdlDestination::Current, "self")
+                | (BdlDestination::Computed { .. }, "computed")
+        ),
+        _ => false,
+    }
+}
+
+fn invalid_selector(selector: &str, reason: &str) -> BdlError {
+    BdlError::ParseError(format!("Invalid selector '{}': {}", selector, reason).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::option;
+
+    fn create_test_document() -> BdlDocument {
+        let mut doc = BdlDocument::new(None);
+
+        let mut shop = BdlNode::new("shop".to_string());
+        shop.tags = vec!["merchant".to_string(), "town".to_string()];
+        shop.add_option(option(&["buy", "purchase"], BdlDestination::Node("counter".to_string())));
+        shop.add_option(option(&["leave"], BdlDestination::Exit));
+        doc.add_node(shop).unwrap();
+
+        let mut counter = BdlNode::new("counter".to_string());
+        counter.tags = vec!["merchant".to_string()];
+        counter.add_option(option(&["buy"], BdlDestination::FileTransfer {
+            file: "bank.bdl".to_string(),
+            node: "pay".to_string(),
+        }));
+        doc.add_node(counter).unwrap();
+
+        doc
+    }
+
+    #[test]
+    fn test_query_nodes_by_name() {
+        let doc = create_test_document();
+
+        let matches = doc.query("node[name=shop]").unwrap();
+        assert_eq!(matches.len(), 1);
+        assert!(matches!(matches[0], QueryMatch::Node(node) if node.name == "shop"));
+
+        assert_eq!(doc.query("node").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_query_nodes_by_tag() {
+        let doc = create_test_document();
+
+        let names = |selector: &str| -> Vec<String> {
+            doc.query(selector)
+                .unwrap()
+                .iter()
+                .map(|m| match m {
+                    QueryMatch::Node(node) => node.name.clone(),
+                    QueryMatch::Option { node, index, .. } => format!("{}#{}", node.name, index),
+                })
+                .collect()
+        };
+        assert_eq!(names("node[tag=merchant]"), vec!["counter", "shop"]);
+        assert_eq!(names("node[tag=town]"), vec!["shop"]);
+        assert!(names("node[tag=castle]").is_empty());
+        assert_eq!(names("node[tag=town] option[keyword=buy]"), vec!["shop#0"]);
+    }
+
+    #[test]
+    fn test_query_options_by_keyword() {
+        let doc = create_test_document();
+
+        let matches = doc.query("option[keyword=buy]").unwrap();
+        let found: Vec<(&str, usize)> = matches
+            .iter()
+            .map(|m| match m {
+                QueryMatch::Option { node, index, .. } => (node.name.as_str(), *index),
+                QueryMatch::Node(_) => panic!("Expected option match"),
+            })
+            .collect();
+        assert_eq!(found, vec![("counter", 0), ("shop", 0)]);
+
+        let matches = doc.query("node[name=shop] option[dest=exit]").unwrap();
+        assert_eq!(matches.len(), 1);
+        assert!(matches!(
+            matches[0],
+            QueryMatch::Option { option, .. } if option.keywords == vec!["leave"]
+        ));
+    }
+
+    #[test]
+    fn test_invalid_selectors() {
+        let doc = create_test_document();
+
+        for selector in ["", "scene", "node[kind=shop]", "option[keyword]", "node[name=a", "option node"] {
+            assert!(
+                matches!(doc.query(selector), Err(BdlError::ParseError(_))),
+                "selector {:?} should be rejected",
+                selector
+            );
+        }
+    }
+}
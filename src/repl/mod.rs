@@ -0,0 +1,136 @@
+use crate::{BdlError, BdlRuntime};
+use std::io::{self, BufRead, Write};
+
+/// Prompt written before each line of input
+pub const REPL_PROMPT: &str = "> ";
+
+/// Plays a dialogue as text: renders each node, lists its options and reads choices
+///
+/// The loop runs over any `BufRead`/`Write` pair, so it can drive a
+/// terminal as well as scripted input in tests.
+#[derive(Debug)]
+pub struct BdlRepl<'a> {
+    runtime: BdlRuntime<'a>,
+}
+
+impl<'a> BdlRepl<'a> {
+    /// Wraps a runtime, which may already have host functions and callbacks registered
+    pub fn new(runtime: BdlRuntime<'a>) -> Self {
+        Self { runtime }
+    }
+
+    /// The runtime being played
+    pub fn runtime(&self) -> &BdlRuntime<'a> {
+        &self.runtime
+    }
+
+    /// Consumes the REPL, returning the runtime in the state play stopped in
+    pub fn into_runtime(self) -> BdlRuntime<'a> {
+        self.runtime
+    }
+
+    /// Plays until an `exit` option is taken or `input` runs out
+    ///
+    /// Each node's text is written followed by its keyword options, one per
+    /// line as `- keyword, synonym`; disabled options are marked
+    /// `(unavailable)`. Keywordless options such as a `---` fallthrough are
+    /// followed without asking. A line that matches no option is answered
+    /// with the error and asked again. Errors from rendering or following an
+    /// option are returned as `io::ErrorKind::Other`.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        let mut line = String::new();
+
+        while !self.runtime.is_finished() {
+            let text = self.runtime.render_content().map_err(io::Error::other)?;
+            if !text.is_empty() {
+                writeln!(output, "{}", text)?;
+            }
+            if self.runtime.advance().map_err(io::Error::other)? {
+                continue;
+            }
+
+            for (option, enabled) in self.runtime.available_options() {
+                if option.keywords.is_empty() {
+                    continue;
+                }
+                let marker = if enabled { "" } else { " (unavailable)" };
+                writeln!(output, "- {}{}", option.keywords.join(", "), marker)?;
+            }
+
+            // Ask until a choice is accepted
+            loop {
+                write!(output, "{}", REPL_PROMPT)?;
+                output.flush()?;
+
+                line.clear();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match self.runtime.choose(&line) {
+                    Ok(()) => break,
+                    Err(e @ BdlError::NodeError(_)) => writeln!(output, "{}", e)?,
+                    Err(e) => return Err(io::Error::other(e)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::BdlParser;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_repl_plays_scripted_input() {
+        let doc = BdlParser::new(
+            r#"@start
+Hello, traveller.
+{shop, buy} -> @shop
+~{vip} {lounge} -> @shop
+
+@shop
+Nothing for sale.
+{bye} -> exit
+"#
+            .to_string(),
+        )
+        .parse()
+        .unwrap();
+
+        let mut repl = BdlRepl::new(BdlRuntime::new(&doc, "start").unwrap());
+        let mut output = Vec::new();
+        repl.run(Cursor::new("dance\n\nBUY\nbye\n"), &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Hello, traveller.\n\
+             - shop, buy\n\
+             - lounge (unavailable)\n\
+             > Node error: No option in 'start' matches 'dance'\n\
+             > > Nothing for sale.\n\
+             - bye\n\
+             > "
+        );
+        assert!(repl.runtime().is_finished());
+    }
+
+    #[test]
+    fn test_repl_stops_when_input_runs_out() {
+        let doc = BdlParser::new("@start\nWait.\n{go} -> @start\n".to_string()).parse().unwrap();
+
+        let mut repl = BdlRepl::new(BdlRuntime::new(&doc, "start").unwrap());
+        let mut output = Vec::new();
+        repl.run(Cursor::new("go\n"), &mut output).unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "Wait.\n- go\n> Wait.\n- go\n> ");
+        let runtime = repl.into_runtime();
+        assert!(!runtime.is_finished());
+    }
+}
@@ -0,0 +1,838 @@
+use crate::{BdlArg, BdlBranchOption, BdlCondition, BdlContentElement, BdlDestination, BdlDocument, BdlError, BdlNode, BdlValue, FormatSpec, normalize_keyword};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Host function called for `!{name}` elements; returns the values for its result variables
+pub type HostFunction = Rc<dyn Fn(&[BdlValue]) -> Result<Vec<BdlValue>, BdlError>>;
+
+/// Host functions available to a runtime, by name
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, HostFunction>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `function` under `name`, replacing any earlier registration
+    pub fn register(
+        &mut self,
+        name: &str,
+        function: impl Fn(&[BdlValue]) -> Result<Vec<BdlValue>, BdlError> + 'static,
+    ) {
+        self.functions.insert(name.to_string(), Rc::new(function));
+    }
+
+    /// Whether a function is registered under `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Calls the function registered under `name`
+    pub fn call(&self, name: &str, args: &[BdlValue]) -> Result<Vec<BdlValue>, BdlError> {
+        let function = self
+            .functions
+            .get(name)
+            .ok_or_else(|| BdlError::VariableError(format!("Function '{}' is not registered", name).into()))?;
+        function(args)
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        f.debug_struct("FunctionRegistry").field("functions", &names).finish()
+    }
+}
+
+/// Callback fired with a node name and the variable store when a node is entered or left
+pub type NodeHook = Rc<dyn Fn(&str, &HashMap<String, BdlValue>)>;
+
+/// Callbacks registered with `BdlRuntime::on_enter` and `BdlRuntime::on_exit`
+#[derive(Clone, Default)]
+struct NodeHooks {
+    enter: Vec<NodeHook>,
+    exit: Vec<NodeHook>,
+}
+
+impl fmt::Debug for NodeHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeHooks")
+            .field("enter", &self.enter.len())
+            .field("exit", &self.exit.len())
+            .finish()
+    }
+}
+
+/// Plays a dialogue: tracks the current node and variables and follows options
+#[derive(Debug, Clone)]
+pub struct BdlRuntime<'a> {
+    document: &'a BdlDocument,
+    current: String,
+    variables: HashMap<String, BdlValue>,
+    functions: FunctionRegistry,
+    hooks: NodeHooks,
+    case_sensitive: bool,
+    finished: bool,
+}
+
+impl<'a> BdlRuntime<'a> {
+    /// Starts at `start`, with the document's global and local variables in the store
+    ///
+    /// Local variables shadow globals of the same name.
+    pub fn new(document: &'a BdlDocument, start: &str) -> Result<Self, BdlError> {
+        Self::with_overrides(document, start, HashMap::new())
+    }
+
+    /// Like `new`, but `overrides` replace declared defaults (or add variables) before play starts
+    ///
+    /// An override must have the same type as the declared default it
+    /// replaces, except that an integer may replace a number; a mismatch is a
+    /// `VariableError`. Variables declared without a value accept any type.
+    pub fn with_overrides(
+        document: &'a BdlDocument,
+        start: &str,
+        overrides: HashMap<String, BdlValue>,
+    ) -> Result<Self, BdlError> {
+        if !document.nodes.contains_key(start) {
+            return Err(BdlError::NodeError(format!("Start node '{}' does not exist", start).into()));
+        }
+
+        let mut variables = document.global_vars.clone().unwrap_or_default();
+        variables.extend(document.local_vars.clone());
+
+        let mut names: Vec<&String> = overrides.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(declared) = variables.get(name) {
+                if !override_fits(declared, &overrides[name]) {
+                    return Err(BdlError::VariableError(format!(
+                        "Override for '{}' does not match the type of its declared default",
+                        name
+                    ).into()));
+                }
+            }
+        }
+        variables.extend(overrides);
+
+        Ok(BdlRuntime {
+            document,
+            current: start.to_string(),
+            variables,
+            functions: FunctionRegistry::new(),
+            hooks: NodeHooks::default(),
+            case_sensitive: false,
+            finished: false,
+        })
+    }
+
+    /// The node the dialogue is currently at
+    ///
+    /// After an `exit` this is the node the exit was taken from.
+    pub fn current_node(&self) -> &'a BdlNode {
+        &self.document.nodes[&self.current]
+    }
+
+    /// Whether an `exit` option has been taken
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The variable store
+    pub fn variables(&self) -> &HashMap<String, BdlValue> {
+        &self.variables
+    }
+
+    /// Sets a variable, e.g. from host input
+    pub fn set_variable(&mut self, name: impl Into<String>, value: BdlValue) {
+        self.variables.insert(name.into(), value);
+    }
+
+    /// Whether `choose` compares input and keywords exactly instead of ignoring case
+    ///
+    /// Off by default. Surrounding whitespace and runs of spaces are ignored
+    /// either way.
+    pub fn set_case_sensitive(&mut self, case_sensitive: bool) {
+        self.case_sensitive = case_sensitive;
+    }
+
+    /// Registers a callback fired each time navigation enters a node
+    ///
+    /// The start node is entered before any callback can be registered, so
+    /// it does not fire for it. Callbacks run in registration order.
+    pub fn on_enter(&mut self, hook: impl Fn(&str, &HashMap<String, BdlValue>) + 'static) {
+        self.hooks.enter.push(Rc::new(hook));
+    }
+
+    /// Registers a callback fired each time navigation leaves a node, including by `exit`
+    pub fn on_exit(&mut self, hook: impl Fn(&str, &HashMap<String, BdlValue>) + 'static) {
+        self.hooks.exit.push(Rc::new(hook));
+    }
+
+    /// The host functions `!{name}` elements are dispatched to
+    pub fn functions_mut(&mut self) -> &mut FunctionRegistry {
+        &mut self.functions
+    }
+
+    /// The current node's text with `${var}` replaced by its value
+    ///
+    /// Function calls run in content order, so a variable shown after a call
+    /// sees its result. Unset variables render as empty text; function calls,
+    /// directives and data blocks produce no text. A `${var:spec}` format
+    /// spec that needs a number is a `VariableError` on any other value.
+    pub fn render_content(&mut self) -> Result<String, BdlError> {
+        let mut text = String::new();
+        for element in &self.current_node().content {
+            match element {
+                BdlContentElement::Text(t) => text.push_str(t),
+                BdlContentElement::Variable(name) => {
+                    if let Some(value) = self.variables.get(name) {
+                        text.push_str(&value_to_text(value));
+                    }
+                }
+                BdlContentElement::FormattedVariable { name, spec } => {
+                    if let Some(value) = self.variables.get(name) {
+                        text.push_str(&format_value(name, value, spec)?);
+                    }
+                }
+                BdlContentElement::Conditional { condition, then, otherwise } => {
+                    text.push_str(if condition.evaluate(&self.variables) { then } else { otherwise });
+                }
+                BdlContentElement::FunctionCall { name, args, result_vars } => self.call_function(name, args, result_vars)?,
+                _ => {}
+            }
+        }
+        Ok(text)
+    }
+
+    /// Runs a host function and stores its results in `result_vars`, in order
+    ///
+    /// Variable arguments are looked up in the store first; an unset one is a
+    /// `VariableError`.
+    fn call_function(&mut self, name: &str, args: &[BdlArg], result_vars: &[String]) -> Result<(), BdlError> {
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                BdlArg::Value(value) => Ok(value.clone()),
+                BdlArg::Variable(variable) => self.variables.get(variable).cloned().ok_or_else(|| {
+                    BdlError::VariableError(
+                        format!("Variable '{}' passed to function '{}' is not set", variable, name).into(),
+                    )
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let results = self.functions.call(name, &args)?;
+        if results.len() < result_vars.len() {
+            return Err(BdlError::VariableError(format!(
+                "Function '{}' returned {} values for {} result variables",
+                name,
+                results.len(),
+                result_vars.len()
+            ).into()));
+        }
+
+        for (variable, value) in result_vars.iter().zip(results) {
+            self.variables.insert(variable.clone(), value);
+        }
+        Ok(())
+    }
+
+    /// Follows the first enabled option whose condition passes and that has `input` as a keyword
+    ///
+    /// Input and keywords are compared with surrounding whitespace trimmed and
+    /// inner runs of whitespace collapsed to one space, ignoring case unless
+    /// `set_case_sensitive(true)` was called. An all-of option (`{+open +door}`)
+    /// matches when every one of its keywords is a word of the input, in any
+    /// order and among other words. An `exit` destination finishes the
+    /// dialogue and `@self` re-enters the current node. For `!{function}` the
+    /// registered host function is called without arguments and must return
+    /// the name of an existing node as a string. File transfers cannot be followed by a single-document
+    /// runtime and are reported as a `DependencyError`.
+    pub fn choose(&mut self, input: &str) -> Result<(), BdlError> {
+        if self.finished {
+            return Err(BdlError::NodeError("The dialogue has already finished".into()));
+        }
+
+        let option = self.find_option(input).ok_or_else(|| {
+            BdlError::NodeError(format!("No option in '{}' matches '{}'", self.current, input.trim()).into())
+        })?;
+
+        self.follow(&option.destination)
+    }
+
+    /// Plays `inputs` in order and returns the names of the nodes visited, starting with the current one
+    ///
+    /// Stops early, without an error, at an `exit` or at the first input no
+    /// option matches; the runtime is left where the script stopped. Options
+    /// without keywords are not followed automatically. Errors from following
+    /// an option, such as a missing node, are returned as from `choose`.
+    pub fn run_script(&mut self, inputs: &[&str]) -> Result<Vec<String>, BdlError> {
+        let mut visited = vec![self.current.clone()];
+        for input in inputs {
+            if self.finished {
+                break;
+            }
+            let Some(option) = self.find_option(input) else {
+                break;
+            };
+            self.follow(&option.destination)?;
+            if !self.finished {
+                visited.push(self.current.clone());
+            }
+        }
+        Ok(visited)
+    }
+
+    /// The current node's options whose condition passes, with whether each is enabled
+    ///
+    /// An option whose `~{...}` condition fails is still listed, e.g. to be
+    /// shown greyed out, but `choose` skips it.
+    pub fn available_options(&self) -> Vec<(&'a BdlBranchOption, bool)> {
+        self.current_node()
+            .options
+            .iter()
+            .filter(|option| self.passes(&option.condition))
+            .map(|option| (option, self.passes(&option.enabled)))
+            .collect()
+    }
+
+    /// Whether an optional condition is absent or holds for the current variables
+    fn passes(&self, condition: &Option<BdlCondition>) -> bool {
+        condition.as_ref().is_none_or(|c| c.evaluate(&self.variables))
+    }
+
+    /// The option `choose` would take for `input`, if any
+    fn find_option(&self, input: &str) -> Option<&'a BdlBranchOption> {
+        let input = self.normalize_input(input);
+        let words: Vec<&str> = input.split(' ').collect();
+        self.available_options()
+            .into_iter()
+            .filter_map(|(option, enabled)| enabled.then_some(option))
+            .find(|option| {
+                if option.match_all {
+                    !option.keywords.is_empty()
+                        && option.keywords.iter().all(|k| words.contains(&self.normalize_input(k).as_str()))
+                } else {
+                    option.keywords.iter().any(|k| self.normalize_input(k) == input)
+                }
+            })
+    }
+
+    /// Follows the first option without keywords whose condition passes
+    ///
+    /// Such options are taken without player input, e.g. the fallthrough
+    /// between unnamed `---` nodes. Returns whether the runtime moved; when
+    /// the node has no such option it stays put.
+    pub fn advance(&mut self) -> Result<bool, BdlError> {
+        if self.finished {
+            return Err(BdlError::NodeError("The dialogue has already finished".into()));
+        }
+
+        let option = self
+            .current_node()
+            .options
+            .iter()
+            .filter(|option| option.keywords.is_empty())
+            .find(|option| self.passes(&option.condition) && self.passes(&option.enabled));
+
+        match option {
+            Some(option) => self.follow(&option.destination).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Moves to `destination`, firing the exit and enter callbacks
+    fn follow(&mut self, destination: &BdlDestination) -> Result<(), BdlError> {
+        match destination {
+            BdlDestination::Node(target) => {
+                if !self.document.nodes.contains_key(target) {
+                    return Err(BdlError::NodeError(format!("Node '{}' does not exist", target).into()));
+                }
+                self.fire(&self.hooks.exit);
+                self.current = target.clone();
+                self.fire(&self.hooks.enter);
+            }
+            BdlDestination::FileTransfer { file, node } => {
+                return Err(BdlError::DependencyError(
+                    format!("Cannot follow transfer to [{}:{}] within one document", file, node).into(),
+                ));
+            }
+            BdlDestination::Exit => {
+                self.fire(&self.hooks.exit);
+                self.finished = true;
+            }
+            BdlDestination::Current => {
+                // Leaves and re-enters the same node
+                self.fire(&self.hooks.exit);
+                self.fire(&self.hooks.enter);
+            }
+            BdlDestination::Computed { function } => {
+                let target = match self.functions.call(function, &[])?.into_iter().next() {
+                    Some(BdlValue::String(target)) => target,
+                    _ => {
+                        return Err(BdlError::NodeError(
+                            format!("Function '{}' did not return a node name", function).into(),
+                        ));
+                    }
+                };
+                return self.follow(&BdlDestination::Node(target));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `hooks` for the current node
+    fn fire(&self, hooks: &[NodeHook]) {
+        for hook in hooks {
+            hook(&self.current, &self.variables);
+        }
+    }
+
+    /// The form of player input, or a keyword, that `choose` compares
+    fn normalize_input(&self, input: &str) -> String {
+        normalize_keyword(input, self.case_sensitive)
+    }
+}
+
+/// Whether `value` may replace the declared default `declared`
+fn override_fits(declared: &BdlValue, value: &BdlValue) -> bool {
+    match (declared, value) {
+        (BdlValue::Empty, _) | (BdlValue::Number(_), BdlValue::Integer(_)) => true,
+        (declared, value) => std::mem::discriminant(declared) == std::mem::discriminant(value),
+    }
+}
+
+/// Text shown for a variable value
+fn value_to_text(value: &BdlValue) -> String {
+    match value {
+        BdlValue::String(s) => s.clone(),
+        BdlValue::Integer(n) => n.to_string(),
+        BdlValue::Number(n) => n.to_string(),
+        BdlValue::Boolean(b) => b.to_string(),
+        BdlValue::List(items) => items.iter().map(value_to_text).collect::<Vec<_>>().join(", "),
+        BdlValue::Map(entries) => {
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort();
+            keys.into_iter()
+                .map(|key| format!("{}: {}", key, value_to_text(&entries[key])))
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        BdlValue::Empty => String::new(),
+    }
+}
+
+/// Render a value under a `${var:spec}` format spec
+fn format_value(name: &str, value: &BdlValue, spec: &FormatSpec) -> Result<String, BdlError> {
+    let width = spec.width.unwrap_or(0);
+    let text = match (value, spec.precision) {
+        (BdlValue::Integer(n), None) if spec.zero_pad => format!("{:0width$}", n),
+        (BdlValue::Integer(n), None) => format!("{:width$}", n),
+        (BdlValue::Number(n), None) if spec.zero_pad => format!("{:0width$}", n),
+        (BdlValue::Number(n), None) => format!("{:width$}", n),
+        (BdlValue::Integer(_) | BdlValue::Number(_), Some(precision)) => {
+            let n = value.as_f64().unwrap_or_default();
+            if spec.zero_pad {
+                format!("{:0width$.precision$}", n)
+            } else {
+                format!("{:width$.precision$}", n)
+            }
+        }
+        _ if spec.zero_pad || spec.precision.is_some() => {
+            return Err(BdlError::VariableError(format!(
+                "Format spec '{}' on variable '{}' needs a number, got '{}'",
+                spec,
+                name,
+                value_to_text(value)
+            ).into()));
+        }
+        _ => format!("{:width$}", value_to_text(value)),
+    };
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{BdlParser, ParserOptions};
+    use std::cell::RefCell;
+
+    fn parse(content: &str) -> BdlDocument {
+        BdlParser::new(content.to_string()).parse().unwrap()
+    }
+
+    fn create_test_document() -> BdlDocument {
+        parse(r#"$local_vars: {
+    name: "Ada"
+    coins: 3
+}
+
+@start
+Hello, ${name}!
+{shop, buy} -> @shop
+
+@shop
+You have ${coins} coins.
+?{vip} {bye} -> @start
+{bye} -> exit
+"#)
+    }
+
+    #[test]
+    fn test_runtime_walks_conversation() {
+        let doc = create_test_document();
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+
+        assert_eq!(runtime.current_node().name, "start");
+        assert_eq!(runtime.render_content().unwrap(), "Hello, Ada!");
+
+        runtime.choose(" buy ").unwrap();
+        assert_eq!(runtime.current_node().name, "shop");
+        assert_eq!(runtime.render_content().unwrap(), "You have 3 coins.");
+
+        // The conditional option is skipped because `vip` is unset
+        runtime.choose("bye").unwrap();
+        assert!(runtime.is_finished());
+        assert!(matches!(runtime.choose("bye"), Err(BdlError::NodeError(_))));
+    }
+
+    #[test]
+    fn test_runtime_seeds_declared_defaults() {
+        let doc = parse(r#"$global_vars: {
+    name: "Ada"
+    coins: 1
+}
+$local_vars: {
+    coins: 3
+}
+
+@start
+${name} has ${coins} coins.
+"#);
+
+        // Nothing is set by hand; locals win over globals
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        assert_eq!(runtime.render_content().unwrap(), "Ada has 3 coins.");
+
+        let overrides = HashMap::from([("name".to_string(), BdlValue::String("Grace".to_string()))]);
+        let mut runtime = BdlRuntime::with_overrides(&doc, "start", overrides).unwrap();
+        assert_eq!(runtime.render_content().unwrap(), "Grace has 3 coins.");
+    }
+
+    #[test]
+    fn test_runtime_format_specs() {
+        let doc = parse(r#"$local_vars: {
+    score: 7
+    price: 3.14159
+    name: "Ada"
+}
+
+@start
+[${score:02}] ${price:.2} ${score:.1} |${name:5}|${score:4}|
+
+@bad
+${name:.2}
+"#);
+
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        assert_eq!(runtime.render_content().unwrap(), "[07] 3.14 7.0 |Ada  |   7|");
+
+        let mut runtime = BdlRuntime::new(&doc, "bad").unwrap();
+        assert!(matches!(
+            runtime.render_content(),
+            Err(BdlError::VariableError(msg)) if msg.contains("'.2'") && msg.contains("'name'")
+        ));
+    }
+
+    #[test]
+    fn test_runtime_inline_conditionals() {
+        let doc = parse(r#"$local_vars: {
+    count: 1
+}
+
+@basket
+You have ${count} ${count == 1 ? "apple" : "apples"}.
+"#);
+
+        let mut runtime = BdlRuntime::new(&doc, "basket").unwrap();
+        assert_eq!(runtime.render_content().unwrap(), "You have 1 apple.");
+
+        runtime.set_variable("count", BdlValue::Integer(3));
+        assert_eq!(runtime.render_content().unwrap(), "You have 3 apples.");
+    }
+
+    #[test]
+    fn test_runtime_overrides_win_and_are_type_checked() {
+        let doc = parse(r#"$local_vars: {
+    coins: 3
+    rate: 1.5
+    note:
+}
+
+@start
+${coins} ${rate} ${note}
+"#);
+
+        let overrides = HashMap::from([
+            ("coins".to_string(), BdlValue::Integer(99)),
+            ("rate".to_string(), BdlValue::Integer(2)),
+            ("note".to_string(), BdlValue::Boolean(true)),
+            ("debug".to_string(), BdlValue::Boolean(true)),
+        ]);
+        let runtime = BdlRuntime::with_overrides(&doc, "start", overrides).unwrap();
+        assert_eq!(runtime.variables()["coins"], BdlValue::Integer(99));
+        assert_eq!(runtime.variables()["rate"], BdlValue::Integer(2));
+        assert_eq!(runtime.variables()["note"], BdlValue::Boolean(true));
+        assert_eq!(runtime.variables()["debug"], BdlValue::Boolean(true));
+
+        let overrides = HashMap::from([("coins".to_string(), BdlValue::String("lots".to_string()))]);
+        assert!(matches!(
+            BdlRuntime::with_overrides(&doc, "start", overrides),
+            Err(BdlError::VariableError(msg)) if msg.contains("'coins'")
+        ));
+    }
+
+    #[test]
+    fn test_runtime_self_loop() {
+        let doc = parse(r#"@riddle
+What walks on four legs?
+{again, repeat} -> @self
+{man} -> exit
+"#);
+        let mut runtime = BdlRuntime::new(&doc, "riddle").unwrap();
+        let entered = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&entered);
+        runtime.on_enter(move |name, _| log.borrow_mut().push(name.to_string()));
+
+        runtime.choose("again").unwrap();
+        assert_eq!(runtime.current_node().name, "riddle");
+        assert!(!runtime.is_finished());
+        assert_eq!(runtime.render_content().unwrap(), "What walks on four legs?");
+        assert_eq!(*entered.borrow(), vec!["riddle"]);
+    }
+
+    #[test]
+    fn test_runtime_disabled_options_stay_listed() {
+        let doc = parse(r#"@vault
+The vault door is shut.
+~{has_key} {open} -> @inside
+{leave} -> exit
+
+@inside
+Gold!
+"#);
+        let mut runtime = BdlRuntime::new(&doc, "vault").unwrap();
+
+        let listed: Vec<(&str, bool)> = runtime
+            .available_options()
+            .iter()
+            .map(|(option, enabled)| (option.keywords[0].as_str(), *enabled))
+            .collect();
+        assert_eq!(listed, vec![("open", false), ("leave", true)]);
+        assert!(matches!(runtime.choose("open"), Err(BdlError::NodeError(_))));
+        assert_eq!(runtime.current_node().name, "vault");
+
+        runtime.set_variable("has_key", BdlValue::Boolean(true));
+        assert!(runtime.available_options()[0].1);
+        runtime.choose("open").unwrap();
+        assert_eq!(runtime.current_node().name, "inside");
+    }
+
+    #[test]
+    fn test_runtime_computed_destination() {
+        let doc = parse(r#"@start
+{ask} -> !{pickNext}
+
+@left
+Left it is.
+
+@right
+Right it is.
+"#);
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        runtime.functions_mut().register("pickNext", |_| Ok(vec![BdlValue::String("right".to_string())]));
+        runtime.choose("ask").unwrap();
+        assert_eq!(runtime.current_node().name, "right");
+
+        // A name that is not a node is an error and the runtime stays put
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        runtime.functions_mut().register("pickNext", |_| Ok(vec![BdlValue::String("middle".to_string())]));
+        assert!(matches!(runtime.choose("ask"), Err(BdlError::NodeError(_))));
+        assert_eq!(runtime.current_node().name, "start");
+
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        runtime.functions_mut().register("pickNext", |_| Ok(vec![BdlValue::Integer(2)]));
+        assert!(matches!(runtime.choose("ask"), Err(BdlError::NodeError(_))));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let doc = create_test_document();
+        assert!(matches!(BdlRuntime::new(&doc, "missing"), Err(BdlError::NodeError(_))));
+
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        assert!(matches!(runtime.choose("dance"), Err(BdlError::NodeError(_))));
+        assert_eq!(runtime.current_node().name, "start");
+
+        runtime.set_variable("name", BdlValue::String("Grace".to_string()));
+        assert_eq!(runtime.render_content().unwrap(), "Hello, Grace!");
+    }
+
+    #[test]
+    fn test_runtime_calls_host_functions() {
+        let mut doc = BdlDocument::new(None);
+        let mut roll = BdlNode::new("roll".to_string());
+        roll.add_content(BdlContentElement::FunctionCall {
+            name: "random".to_string(),
+            args: Vec::new(),
+            result_vars: vec!["die".to_string()],
+        });
+        roll.add_content(BdlContentElement::Text("You rolled ".to_string()));
+        roll.add_content(BdlContentElement::Variable("die".to_string()));
+        doc.add_node(roll).unwrap();
+
+        let mut runtime = BdlRuntime::new(&doc, "roll").unwrap();
+        assert!(matches!(
+            runtime.render_content(),
+            Err(BdlError::VariableError(msg)) if msg.contains("random")
+        ));
+
+        runtime.functions_mut().register("random", |_| Ok(vec![BdlValue::Integer(4)]));
+        assert_eq!(runtime.render_content().unwrap(), "You rolled 4");
+        assert_eq!(runtime.variables().get("die"), Some(&BdlValue::Integer(4)));
+
+        runtime.functions_mut().register("random", |_| Ok(Vec::new()));
+        assert!(matches!(runtime.render_content(), Err(BdlError::VariableError(_))));
+    }
+
+    #[test]
+    fn test_runtime_passes_function_arguments() {
+        let mut doc = BdlDocument::new(None);
+        doc.local_vars.insert("score".to_string(), BdlValue::Integer(32));
+        let mut tally = BdlNode::new("tally".to_string());
+        tally.add_content(BdlContentElement::FunctionCall {
+            name: "add".to_string(),
+            args: vec![
+                BdlArg::Variable("score".to_string()),
+                BdlArg::Value(BdlValue::Integer(10)),
+            ],
+            result_vars: vec!["total".to_string()],
+        });
+        tally.add_content(BdlContentElement::Text("Total: ".to_string()));
+        tally.add_content(BdlContentElement::Variable("total".to_string()));
+        doc.add_node(tally).unwrap();
+
+        let mut runtime = BdlRuntime::new(&doc, "tally").unwrap();
+        runtime.functions_mut().register("add", |args| {
+            let sum = args.iter().map(|arg| match arg {
+                BdlValue::Integer(n) => Ok(*n),
+                other => Err(BdlError::VariableError(format!("Cannot add {:?}", other).into())),
+            });
+            Ok(vec![BdlValue::Integer(sum.sum::<Result<i64, _>>()?)])
+        });
+        assert_eq!(runtime.render_content().unwrap(), "Total: 42");
+
+        runtime.variables.remove("score");
+        assert!(matches!(
+            runtime.render_content(),
+            Err(BdlError::VariableError(msg)) if msg.contains("score")
+        ));
+    }
+
+    #[test]
+    fn test_runtime_keyword_matching() {
+        let doc = parse("@start\n{next} -> @middle\n@middle\n{go  home} -> exit\n");
+
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        runtime.choose("NEXT").unwrap();
+        assert_eq!(runtime.current_node().name, "middle");
+        runtime.choose("Go \t Home ").unwrap();
+        assert!(runtime.is_finished());
+
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        runtime.set_case_sensitive(true);
+        assert!(matches!(runtime.choose("NEXT"), Err(BdlError::NodeError(_))));
+        runtime.choose("next ").unwrap();
+        assert_eq!(runtime.current_node().name, "middle");
+    }
+
+    #[test]
+    fn test_runtime_node_hooks() {
+        let doc = create_test_document();
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&events);
+        runtime.on_enter(move |name, variables| {
+            log.borrow_mut().push(format!("enter {} with {:?}", name, variables.get("name")));
+        });
+        let log = Rc::clone(&events);
+        runtime.on_exit(move |name, _| log.borrow_mut().push(format!("exit {}", name)));
+
+        runtime.choose("shop").unwrap();
+        assert_eq!(
+            *events.borrow(),
+            vec!["exit start", r#"enter shop with Some(String("Ada"))"#]
+        );
+
+        // A failed choice fires nothing; leaving through `exit` fires on_exit only
+        assert!(runtime.choose("dance").is_err());
+        runtime.choose("bye").unwrap();
+        assert_eq!(events.borrow().len(), 3);
+        assert_eq!(events.borrow()[2], "exit shop");
+    }
+
+    #[test]
+    fn test_runtime_follows_anonymous_fallthrough() {
+        let content = "---\nIt was a dark night.\n---\nA door creaked.\n{run} -> @escape\n@escape\nYou ran.\n{exit}\n";
+        let options = ParserOptions {
+            auto_name_anonymous: true,
+            ..Default::default()
+        };
+        let doc = BdlParser::with_options(content.to_string(), options).parse().unwrap();
+
+        let mut runtime = BdlRuntime::new(&doc, "__auto_0").unwrap();
+        assert!(matches!(runtime.choose(""), Err(BdlError::NodeError(_))));
+        assert!(runtime.advance().unwrap());
+        assert_eq!(runtime.render_content().unwrap(), "A door creaked.");
+
+        // Options with keywords still wait for input
+        assert!(!runtime.advance().unwrap());
+        runtime.choose("run").unwrap();
+        assert_eq!(runtime.current_node().name, "escape");
+    }
+
+    #[test]
+    fn test_run_script() {
+        let doc = create_test_document();
+
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        assert_eq!(runtime.run_script(&["buy", "bye", "again"]).unwrap(), vec!["start", "shop"]);
+        assert!(runtime.is_finished());
+
+        // An unmatched input ends the script where it is
+        let mut runtime = BdlRuntime::new(&doc, "start").unwrap();
+        assert_eq!(runtime.run_script(&["shop", "dance", "bye"]).unwrap(), vec!["start", "shop"]);
+        assert!(!runtime.is_finished());
+        assert_eq!(runtime.current_node().name, "shop");
+    }
+
+    #[test]
+    fn test_runtime_all_of_keywords() {
+        let doc = parse("@hall\n{+open +door} -> @doorway\n{open} -> @hall\n@doorway\n{exit}\n");
+
+        // A partial match falls through to the any-of option
+        let mut runtime = BdlRuntime::new(&doc, "hall").unwrap();
+        runtime.choose("open").unwrap();
+        assert_eq!(runtime.current_node().name, "hall");
+        assert!(matches!(runtime.choose("door"), Err(BdlError::NodeError(_))));
+
+        runtime.choose("please OPEN the  door").unwrap();
+        assert_eq!(runtime.current_node().name, "doorway");
+    }
+}